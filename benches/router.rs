@@ -1,6 +1,6 @@
 use std::{hint::black_box, path::Path, time::Duration};
 
-use a_blog_out_of_deep_space::router;
+use a_blog_out_of_deep_space::{Config, router};
 use axum::{
     body::Body,
     extract::Request,
@@ -37,6 +37,16 @@ fn root_compressed(bencher: Bencher) {
     bench_req(bencher, req);
 }
 
+#[bench]
+fn root_compression_disabled(bencher: Bencher) {
+    let req = Request::get("/index.html").header(header::ACCEPT_ENCODING, "deflate, br");
+    let config = Config {
+        encodings: Vec::new(),
+        ..Config::default()
+    };
+    bench_req_with_config(bencher, config, req);
+}
+
 #[bench]
 fn not_found(bencher: Bencher) {
     let req = Request::get("/not-found");
@@ -57,7 +67,7 @@ fn revalidation(bencher: Bencher) {
 
     // and benchmark revalidating with the etag
     let revalidate = req_parts(Request::get("/").header(header::IF_NONE_MATCH, etag));
-    bench_req_with_rt(bencher, rt, revalidate);
+    bench_req_with_rt(bencher, rt, Config::default(), revalidate);
 }
 
 fn req_parts(req: request::Builder) -> request::Parts {
@@ -66,7 +76,7 @@ fn req_parts(req: request::Builder) -> request::Parts {
 
 async fn call_req(req: Request) -> Response {
     let dir = Path::new("tests").join("assets").join("site");
-    let mut app = router(dir);
+    let mut app = router(dir, Config::default()).unwrap();
     <_ as ServiceExt<Request>>::ready(&mut app)
         .await
         .unwrap()
@@ -76,20 +86,24 @@ async fn call_req(req: Request) -> Response {
 }
 
 fn bench_req(bencher: Bencher, req: request::Builder) {
+    bench_req_with_config(bencher, Config::default(), req);
+}
+
+fn bench_req_with_config(bencher: Bencher, config: Config, req: request::Builder) {
     let parts = req_parts(req);
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_time()
         .build()
         .unwrap();
 
-    bench_req_with_rt(bencher, rt, parts);
+    bench_req_with_rt(bencher, rt, config, parts);
 }
 
-fn bench_req_with_rt(bencher: Bencher, rt: Runtime, parts: request::Parts) {
+fn bench_req_with_rt(bencher: Bencher, rt: Runtime, config: Config, parts: request::Parts) {
     let dir = Path::new("tests").join("assets").join("site");
     // TODO: add etag revalidation?
     // NOTE: internally uses `tokio::spawn`, so must be run from an async context
-    let mut app = rt.block_on(async { router(dir) });
+    let mut app = rt.block_on(async { router(dir, config).unwrap() });
     bencher.counter(1u32).bench_local(|| {
         rt.block_on(async {
             let req = Request::from_parts(black_box(parts.clone()), Body::empty());
@@ -0,0 +1,334 @@
+//! Replays an access log against the router to warm up or load test it
+//!
+//! `cargo run --release --example replay -- <DIR> <ACCESS_LOG> [--timed] [--concurrency <N>]`
+//!
+//! The log can be in either of the recorder's formats. Requests run in-process against
+//! `router()`, so this measures the server itself without any networking noise. By default they
+//! fire as fast as possible, while `--timed` preserves the gaps between the logged requests
+//!
+//! Access logs don't record request headers, so every request asks for `Accept-Encoding: br,
+//! gzip` like a typical browser would
+
+use std::{
+    env, fs,
+    path::PathBuf,
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use a_blog_out_of_deep_space::{Config, router};
+use axum::{
+    Router,
+    body::{self, Body},
+    extract::Request,
+    http::{Method, header},
+};
+use serde::Deserialize;
+use tokio::time::Instant;
+use tower::ServiceExt;
+
+const USAGE: &str = "replay <DIR> <ACCESS_LOG> [--timed] [--concurrency <N>]";
+
+#[tokio::main]
+async fn main() {
+    let Args {
+        dir,
+        access_log,
+        timed,
+        concurrency,
+    } = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("error: {err}\n\nUsage: {USAGE}");
+            process::exit(1);
+        }
+    };
+
+    let log = fs::read_to_string(&access_log).unwrap_or_else(|err| {
+        eprintln!("Failed reading {}: {err}", access_log.display());
+        process::exit(1);
+    });
+    let mut entries = Vec::new();
+    for (i, line) in log.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match LogEntry::parse(line) {
+            Some(entry) => entries.push(entry),
+            None => eprintln!("Skipping malformed line {}", i + 1),
+        }
+    }
+    if entries.is_empty() {
+        eprintln!("No requests to replay");
+        process::exit(1);
+    }
+
+    let mut config = Config::load(&dir).unwrap_or_else(|err| {
+        eprintln!("Failed loading config: {err}");
+        process::exit(1);
+    });
+    // replaying shouldn't append to the very log that's getting replayed
+    config.access_log = None;
+    let app = router(dir, config).unwrap_or_else(|err| {
+        eprintln!("Failed loading directory: {err}");
+        process::exit(1);
+    });
+
+    let start = Instant::now();
+    let mut latencies = if timed {
+        replay_timed(app, entries).await
+    } else {
+        replay_flat_out(app, entries, concurrency).await
+    };
+    let elapsed = start.elapsed();
+
+    latencies.sort_unstable();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "{} requests in {:.3?} ({:.1} req/s)",
+        latencies.len(),
+        elapsed,
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+    );
+    println!(
+        "latency: p50 {:.3?}, p90 {:.3?}, p99 {:.3?}, max {:.3?}",
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        percentile(100),
+    );
+}
+
+/// Sends each request at the same offset from the start as it had in the log
+async fn replay_timed(app: Router, entries: Vec<LogEntry>) -> Vec<Duration> {
+    let first = entries
+        .iter()
+        .map(|entry| entry.timestamp)
+        .fold(f64::MAX, f64::min);
+    let start = Instant::now();
+    let tasks: Vec<_> = entries
+        .into_iter()
+        .map(|entry| {
+            let app = app.clone();
+            let at = start + Duration::from_secs_f64(entry.timestamp - first);
+            tokio::spawn(async move {
+                tokio::time::sleep_until(at).await;
+                send(app, &entry).await
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        latencies.push(task.await.unwrap());
+    }
+    latencies
+}
+
+/// Sends requests back to back from `concurrency` workers
+async fn replay_flat_out(app: Router, entries: Vec<LogEntry>, concurrency: usize) -> Vec<Duration> {
+    let entries = Arc::new(entries);
+    let next = Arc::new(AtomicUsize::new(0));
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let app = app.clone();
+            let entries = Arc::clone(&entries);
+            let next = Arc::clone(&next);
+            tokio::spawn(async move {
+                let mut latencies = Vec::new();
+                while let Some(entry) = entries.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    latencies.push(send(app.clone(), entry).await);
+                }
+                latencies
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(entries.len());
+    for worker in workers {
+        latencies.extend(worker.await.unwrap());
+    }
+    latencies
+}
+
+/// Returns how long it took to get the full response
+async fn send(app: Router, entry: &LogEntry) -> Duration {
+    let req = Request::builder()
+        .method(entry.method.clone())
+        .uri(&entry.path)
+        .header(header::ACCEPT_ENCODING, "br, gzip")
+        .body(Body::empty())
+        .unwrap();
+    let start = Instant::now();
+    let resp = app.oneshot(req).await.unwrap();
+    body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    start.elapsed()
+}
+
+#[derive(Debug)]
+struct Args {
+    dir: PathBuf,
+    access_log: PathBuf,
+    timed: bool,
+    concurrency: usize,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut positional = Vec::new();
+        let mut timed = false;
+        let mut concurrency = 1;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--timed" => timed = true,
+                "--concurrency" => {
+                    concurrency = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|&n| n > 0)
+                        .ok_or("'--concurrency' takes a positive number")?;
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown flag '{flag}'")),
+                _ => positional.push(arg),
+            }
+        }
+        let [dir, access_log] = <[_; 2]>::try_from(positional)
+            .map_err(|_| "expected a directory and an access log".to_owned())?;
+        Ok(Self {
+            dir: dir.into(),
+            access_log: access_log.into(),
+            timed,
+            concurrency,
+        })
+    }
+}
+
+/// The parts of an access log entry that are needed to replay it
+#[derive(Debug, PartialEq)]
+struct LogEntry {
+    /// Seconds since the unix epoch
+    timestamp: f64,
+    method: Method,
+    path: String,
+}
+
+impl LogEntry {
+    fn parse(line: &str) -> Option<Self> {
+        if line.starts_with('{') {
+            Self::parse_json(line)
+        } else {
+            Self::parse_combined(line)
+        }
+    }
+
+    fn parse_json(line: &str) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct Json {
+            timestamp: String,
+            method: String,
+            path: String,
+        }
+
+        let Json {
+            timestamp,
+            method,
+            path,
+        } = serde_json::from_str(line).ok()?;
+        // `1994-11-06T08:49:37.250Z`
+        let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+        let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+        let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+        let (hms, millis) = time.split_once('.').unwrap_or((time, "0"));
+        let seconds = seconds_of_day(hms)? + millis.parse::<f64>().ok()? / 1_000.0;
+        Some(Self {
+            timestamp: days_from_civil(year, month, day) as f64 * 86_400.0 + seconds,
+            method: method.parse().ok()?,
+            path,
+        })
+    }
+
+    fn parse_combined(line: &str) -> Option<Self> {
+        // `127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] "GET /posts/ HTTP/1.1" 200 654 ...`
+        let (_, rest) = line.split_once('[')?;
+        let (timestamp, rest) = rest.split_once(']')?;
+        let (_, request) = rest.split_once('"')?;
+        let (request, _) = request.split_once('"')?;
+        let mut request = request.split(' ');
+        let (method, path) = (request.next()?, request.next()?);
+
+        let (date, time) = timestamp.strip_suffix(" +0000")?.split_once(':')?;
+        let mut date = date.splitn(3, '/');
+        let day = date.next()?.parse().ok()?;
+        let month = date.next()?;
+        let month = MONTHS.iter().position(|&name| name == month)? as i64 + 1;
+        let year = date.next()?.parse().ok()?;
+        Some(Self {
+            timestamp: days_from_civil(year, month, day) as f64 * 86_400.0 + seconds_of_day(time)?,
+            method: method.parse().ok()?,
+            path: path.to_owned(),
+        })
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Parses `HH:MM:SS`
+fn seconds_of_day(hms: &str) -> Option<f64> {
+    let mut parts = hms.splitn(3, ':').map(str::parse::<u32>);
+    let (hour, minute, second) = (
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+    );
+    Some(f64::from(hour * 3_600 + minute * 60 + second))
+}
+
+/// Days since the unix epoch for a date in the proleptic gregorian calendar
+///
+/// <https://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // both lines are what the recorder writes for the same request in its own tests
+
+    #[test]
+    fn parses_combined_line() {
+        let line = "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /posts/?page=2 HTTP/1.1\" 200 \
+            654 \"-\" \"curl/8.0 \\\"quoted\\\"\" 1.500";
+        let expected = LogEntry {
+            // the combined format only has whole seconds
+            timestamp: 784_111_777.0,
+            method: Method::GET,
+            path: "/posts/?page=2".into(),
+        };
+        assert_eq!(LogEntry::parse(line), Some(expected));
+    }
+
+    #[test]
+    fn parses_json_line() {
+        let line = r#"{"client_ip":"127.0.0.1","timestamp":"1994-11-06T08:49:37.250Z","method":"GET","path":"/posts/?page=2","version":"HTTP/1.1","status":200,"bytes":654,"referer":null,"user_agent":"curl/8.0 \"quoted\"","duration_ms":1.5}"#;
+        let expected = LogEntry {
+            timestamp: 784_111_777.25,
+            method: Method::GET,
+            path: "/posts/?page=2".into(),
+        };
+        assert_eq!(LogEntry::parse(line), Some(expected));
+    }
+}
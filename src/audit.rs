@@ -0,0 +1,37 @@
+use std::{io, path::Path};
+
+use crate::{config::Config, file::ServedFile, router::walk};
+
+pub struct PoorCompression {
+    pub path: String,
+    /// `None` when the file isn't valid UTF-8, so it couldn't be compressed at all
+    pub ratio: Option<f32>,
+}
+
+/// Loads every file in `dir` collecting all of the files whose compression ratio is over
+/// the configured [`Config::poor_compression_ratio`]
+///
+/// A poor ratio typically means that binary content is hiding behind a text extension. Files that
+/// aren't valid UTF-8 at all get reported too since they're skipped when serving
+pub fn audit_compression(dir: &Path, config: &Config) -> Vec<PoorCompression> {
+    walk(dir)
+        .filter_map(|(path, rel_path)| {
+            let ratio = match ServedFile::load(
+                &path,
+                config.content_type_for(&rel_path)?,
+                config.compression_for(&rel_path),
+                config.weak_e_tag_min_size,
+            ) {
+                Ok(file) => Some(file.compression_ratio()?),
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => None,
+                Err(_) => return None,
+            };
+            ratio
+                .is_none_or(|ratio| ratio > config.poor_compression_ratio)
+                .then_some(PoorCompression {
+                    path: rel_path,
+                    ratio,
+                })
+        })
+        .collect()
+}
@@ -0,0 +1,71 @@
+//! A persistent cache of compressed files to skip recompressing unchanged files across restarts
+//!
+//! Each entry is a checksum of the compressed bytes followed by the bytes themselves, so that
+//! truncated or otherwise corrupt entries get caught and recomputed instead of served
+
+use std::{
+    fs, io, mem,
+    path::Path,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use twox_hash::XxHash64;
+
+pub(crate) const CHECKSUM_SEED: u64 = 0xcac4e;
+const CHECKSUM_LEN: usize = mem::size_of::<u64>();
+
+/// Reads the `key` entry from the cache in `dir` falling back to computing it with `compress`
+///
+/// Freshly computed entries get written back to the cache. Failing to do so only costs us
+/// recompressing on the next startup, so it's logged and otherwise ignored
+pub(crate) fn get_or_insert_with(
+    dir: &Path,
+    key: &str,
+    compress: impl FnOnce() -> Vec<u8>,
+) -> Vec<u8> {
+    let path = dir.join(key);
+    match read_entry(&path) {
+        Ok(Some(compressed)) => return compressed,
+        Ok(None) => tracing::warn!(path = %path.display(), "Recomputing corrupt cache entry"),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => tracing::warn!(path = %path.display(), %err, "Failed reading cache entry"),
+    }
+
+    let compressed = compress();
+    if let Err(err) = write_entry(dir, key, &compressed) {
+        tracing::warn!(path = %path.display(), %err, "Failed writing cache entry");
+    }
+    compressed
+}
+
+/// Returns `None` when the entry fails its checksum
+fn read_entry(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut entry = fs::read(path)?;
+    if entry.len() < CHECKSUM_LEN {
+        return Ok(None);
+    }
+    let compressed = entry.split_off(CHECKSUM_LEN);
+    let checksum = u64::from_le_bytes(entry.try_into().expect("split at the checksum's length"));
+    let is_valid = checksum == XxHash64::oneshot(CHECKSUM_SEED, &compressed);
+    Ok(is_valid.then_some(compressed))
+}
+
+fn write_entry(dir: &Path, key: &str, compressed: &[u8]) -> io::Result<()> {
+    // identical files share an entry, so they can get written concurrently. writing to a unique
+    // temporary file first and renaming it into place keeps readers from ever seeing a partial
+    // entry
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    fs::create_dir_all(dir)?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let tmp_path = dir.join(format!("{key}.{}-{id}.tmp", process::id()));
+    let checksum = XxHash64::oneshot(CHECKSUM_SEED, compressed);
+    let mut entry = Vec::with_capacity(CHECKSUM_LEN + compressed.len());
+    entry.extend_from_slice(&checksum.to_le_bytes());
+    entry.extend_from_slice(compressed);
+    fs::write(&tmp_path, entry)?;
+    fs::rename(&tmp_path, dir.join(key)).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp_path);
+    })
+}
@@ -0,0 +1,802 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    extract::Encoding,
+    file::{CompressionConfig, ContentType, CustomType},
+};
+
+use axum::http::{HeaderValue, StatusCode};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use regex_lite::Regex;
+use serde::{Deserialize, Deserializer, de};
+
+/// Knobs for how a directory gets served
+///
+/// Typically loaded from the [`Config::FILE_NAME`] file in the root of the served directory with
+/// every field being optional
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// A path prefix like `/blog` to serve everything under instead of `/`
+    ///
+    /// Requests outside of the prefix are not found while `/blog` and `/blog/` are treated like
+    /// `/` is without one. Redirects get the prefix too, [`RootFallback::Redirect`] included
+    pub mount_prefix: String,
+    /// A file (relative to the served directory) to serve for a bare `/`
+    ///
+    /// This only takes over `/` itself. The root `index.html` is still reachable at `/index.html`
+    /// and subdirectories still use their own `index.html`s
+    pub root_document: Option<String>,
+    /// See [`RootFallback`]. Left unset `/` is simply not found when nothing else serves it
+    pub root_fallback: Option<RootFallback>,
+    /// Path prefixes like `/app` of client-side routed single-page apps
+    ///
+    /// Unknown paths under one get its `index.html` instead of a `404`, so that the app's router
+    /// can take over. Paths whose last segment has an extension like `/app/main.js` are assets,
+    /// so they're still not found to keep broken references visible
+    pub spa_roots: Vec<String>,
+    /// See [`TrailingSlash`]
+    pub trailing_slash: TrailingSlash,
+    /// See [`UrlUnsafeNames`]
+    pub url_unsafe_names: UrlUnsafeNames,
+    /// See [`Limits`]
+    pub limits: Limits,
+    /// Files that are always stored and served uncompressed even when their content type is
+    /// compressible
+    pub incompressible: PathGlobs,
+    /// Overrides whether files of a content type get compressed, e.g. `{ html = false }`
+    ///
+    /// Text types are compressible by default. Binary types are already compressed internally, so
+    /// they can't be turned on
+    #[serde(deserialize_with = "deserialize_compressible_types")]
+    pub compressible_types: BTreeMap<ContentType, bool>,
+    /// Serves files with these extensions (without the leading `.`) as a [`ExtraType`] on top of
+    /// the built-in types, e.g. `vtt = { mime = "text/vtt", compressible = true }`
+    pub extra_types: BTreeMap<String, ExtraType>,
+    /// The encodings that compressible files get stored and served with. Fewer encodings uses
+    /// less memory at the cost of serving clients that don't accept any of them uncompressed, and
+    /// no encodings disables compression entirely
+    pub encodings: Vec<Encoding>,
+    /// Clients that mishandle compression get served uncompressed no matter their
+    /// `Accept-Encoding`. See [`UserAgentPatterns`]
+    pub identity_user_agents: UserAgentPatterns,
+    /// The base 2 log of the brotli window size from 10 to 24
+    ///
+    /// Some memory-constrained clients can't decode the 4 MiB window of the default 22. Lowering
+    /// it only costs some compression, so [`Config::poor_compression_ratio`] may need loosening
+    #[serde(deserialize_with = "deserialize_lgwin")]
+    pub brotli_lgwin: u32,
+    /// A directory to persist compressed files in, so that unchanged files don't get recompressed
+    /// across restarts. Keep it outside of the served directory
+    ///
+    /// Entries are keyed by a hash of the file's contents and never get evicted
+    pub compression_cache: Option<PathBuf>,
+    /// The compressed (brotli if enabled, otherwise gzip) to uncompressed size ratio above which a
+    /// compressed file is considered to compress poorly
+    pub poor_compression_ratio: f32,
+    /// Where and how to write an access log. Left unset no access log is written
+    pub access_log: Option<AccessLog>,
+    /// See [`CacheScopes`]
+    pub cache_scope: CacheScopes,
+    /// Files that must never be cached. This takes precedence over [`Config::cache_scope`]
+    pub no_store: PathGlobs,
+    /// Treats every file (status code pages included) like it's in [`Config::no_store`], so that
+    /// nothing gets cached or revalidated. Handy for tests that would otherwise see stale content
+    pub no_cache: bool,
+    /// See [`Probes`]
+    pub probes: Probes,
+    /// How many seconds clients get told to wait before retrying when we're overloaded
+    pub retry_after_secs: u64,
+    /// How many seconds open connections get to finish up on shutdown before they're forcibly
+    /// closed
+    pub drain_timeout_secs: u64,
+    /// The directory (relative to the served directory) holding `<CODE>.html` status code pages
+    ///
+    /// Nothing in this directory is served directly. Use `""` to pick up status code pages from
+    /// the root of the site instead, although that makes the pages unreachable as normal content
+    pub status_page_dir: String,
+    /// The status codes whose page in the status page dir gets used as that status' error page
+    #[serde(deserialize_with = "deserialize_statuses")]
+    pub status_pages: BTreeSet<StatusCode>,
+    /// How many files get loaded (and compressed) in parallel. Defaults to the available
+    /// parallelism
+    pub load_threads: Option<NonZeroUsize>,
+    /// Files at least this many bytes get a weak e-tag derived from their size, modification
+    /// time, and first and last 64 KiB instead of a hash of their entire contents
+    pub weak_e_tag_min_size: Option<u64>,
+    /// How far ahead of the server's clock an `If-Modified-Since` date can be before it gets
+    /// ignored
+    pub max_clock_skew_secs: u64,
+    /// See [`Robots`]. Left unset a missing `robots.txt` is simply not found
+    pub robots: Option<Robots>,
+    /// See [`StartupSummary`]
+    pub startup_summary: StartupSummary,
+    /// See [`Throttle`]. Only meant for local testing
+    pub throttle: Option<Throttle>,
+    /// Adds an `X-ABOODS-Debug` header to responses describing the chosen encoding, whether it
+    /// was a revalidation, the cache control, and the matched route
+    ///
+    /// This leaks internals, so it's only meant for debugging
+    pub debug_header: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mount_prefix: String::new(),
+            root_document: None,
+            root_fallback: None,
+            spa_roots: Vec::new(),
+            trailing_slash: TrailingSlash::default(),
+            url_unsafe_names: UrlUnsafeNames::default(),
+            limits: Limits::default(),
+            incompressible: PathGlobs::default(),
+            compressible_types: BTreeMap::new(),
+            extra_types: BTreeMap::new(),
+            encodings: vec![Encoding::Gzip, Encoding::Brotli],
+            identity_user_agents: UserAgentPatterns::default(),
+            brotli_lgwin: CompressionConfig::DEFAULT_BROTLI_LGWIN,
+            compression_cache: None,
+            poor_compression_ratio: CompressionConfig::DEFAULT_POOR_RATIO,
+            access_log: None,
+            cache_scope: CacheScopes::default(),
+            no_store: PathGlobs::default(),
+            no_cache: false,
+            probes: Probes::default(),
+            retry_after_secs: 5,
+            drain_timeout_secs: 30,
+            status_page_dir: "_status".into(),
+            status_pages: [
+                StatusCode::NOT_FOUND,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ]
+            .into(),
+            load_threads: None,
+            weak_e_tag_min_size: None,
+            max_clock_skew_secs: 60,
+            robots: None,
+            startup_summary: StartupSummary::default(),
+            throttle: None,
+            debug_header: false,
+        }
+    }
+}
+
+impl Config {
+    pub const FILE_NAME: &str = "config.toml";
+
+    /// Loads the config from the served directory falling back to the default config when there is
+    /// no config file
+    ///
+    /// A single served file never has a config file
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        if dir.is_file() {
+            return Ok(Self::default());
+        }
+        match fs::read_to_string(dir.join(Self::FILE_NAME)) {
+            Ok(text) => toml::from_str(&text).map_err(io::Error::other),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// [`Config::mount_prefix`] with a leading and no trailing slash, or `""` when there is none
+    pub(crate) fn normalized_mount_prefix(&self) -> String {
+        match self.mount_prefix.trim_matches('/') {
+            "" => String::new(),
+            prefix => format!("/{prefix}"),
+        }
+    }
+
+    /// The cache scope of the file at `rel_path`
+    pub(crate) fn cache_scope_for(&self, rel_path: &str, ty: &ContentType) -> CacheScope {
+        if self.no_cache || self.no_store.is_match(rel_path) {
+            CacheScope::NoStore
+        } else {
+            self.cache_scope.resolve(rel_path, ty)
+        }
+    }
+
+    /// The type of the file at `rel_path` if it's one that gets served
+    pub(crate) fn content_type_for(&self, rel_path: &str) -> Option<ContentType> {
+        let builtin = ContentType::from_rel_path(rel_path);
+        let extra = rel_path
+            .rsplit('/')
+            .next()
+            .and_then(|file_name| file_name.rsplit_once('.'))
+            .filter(|(stem, _)| !stem.is_empty())
+            .and_then(|(_, ext)| self.extra_types.get(ext));
+        match extra {
+            Some(extra) if builtin.is_none() || extra.override_builtin => {
+                Some(ContentType::Custom(CustomType {
+                    mime: extra.mime.clone(),
+                    compressible: extra.compressible,
+                }))
+            }
+            _ => builtin,
+        }
+    }
+
+    /// How the file at `rel_path` gets compressed
+    pub(crate) fn compression_for(&self, rel_path: &str) -> CompressionConfig<'_> {
+        let type_disabled = self
+            .content_type_for(rel_path)
+            .and_then(|ty| self.compressible_types.get(&ty))
+            .is_some_and(|&compressible| !compressible);
+        let encodings = if type_disabled || self.incompressible.is_match(rel_path) {
+            &[]
+        } else {
+            &*self.encodings
+        };
+        CompressionConfig {
+            encodings,
+            brotli_lgwin: self.brotli_lgwin,
+            cache_dir: self.compression_cache.as_deref(),
+            poor_ratio: self.poor_compression_ratio,
+        }
+    }
+}
+
+/// How paths with and without a trailing slash get treated
+///
+/// Directories (anything with an `index.html`) can be requested with or without a trailing slash
+/// while ordinary files only exist without one. The policy decides whether those variants are
+/// served as is, or redirected (`301 Moved Permanently`) to a single canonical form. Either way the
+/// directory's `/index.html` path is unaffected and always served directly, and so is `/`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrailingSlash {
+    /// Directories are served both with and without a trailing slash and files only without one
+    #[default]
+    Preserve,
+    /// Redirect `/posts/` to `/posts` and likewise `/about.html/` to `/about.html`
+    RedirectToNoSlash,
+    /// Redirect `/posts` to `/posts/`, but files still redirect `/about.html/` to `/about.html`
+    RedirectToSlash,
+}
+
+/// A content type for an extension that's either unknown or overridden
+#[derive(Clone, Debug)]
+pub struct ExtraType {
+    /// The full `Content-Type` including any parameters like `charset=utf-8`
+    pub mime: HeaderValue,
+    /// Whether the files get compressed which requires that they're UTF-8 text
+    pub compressible: bool,
+    /// Built-in types win over extra types for the same extension unless this is set. Spelled
+    /// `override` in the config
+    pub override_builtin: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawExtraType {
+    mime: String,
+    #[serde(default)]
+    compressible: bool,
+    #[serde(default, rename = "override")]
+    override_builtin: bool,
+}
+
+impl<'de> Deserialize<'de> for ExtraType {
+    /// The header value gets built and checked once here, so that serving only has to clone it
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let RawExtraType {
+            mime,
+            compressible,
+            override_builtin,
+        } = RawExtraType::deserialize(deserializer)?;
+        // header values can hold arbitrary bytes, but a content type is only ever visible ASCII
+        let mime = HeaderValue::from_str(&mime)
+            .ok()
+            .filter(|value| value.to_str().is_ok())
+            .ok_or_else(|| de::Error::custom(format!("invalid content type {mime:?}")))?;
+        Ok(Self {
+            mime,
+            compressible,
+            override_builtin,
+        })
+    }
+}
+
+/// What to do with files whose names have characters that can't appear as is in a URL's path
+///
+/// e.g. a `?` or `#` would get parsed as the start of the query or fragment, and a client would
+/// send a space percent-encoded as `%20`. Either way a warning gets logged when loading
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UrlUnsafeNames {
+    /// Serve the file from its percent-encoded path, so `c#.html` is at `/c%23.html`
+    #[default]
+    Encode,
+    /// Don't serve the file at all
+    Skip,
+}
+
+/// Guards against loading more than we can hold given that everything gets loaded into memory
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The maximum size in bytes of any single file on disk
+    pub max_file_size: Option<u64>,
+    /// The maximum size in bytes that all loaded files can take up in memory
+    pub max_total_size: Option<u64>,
+    /// Whether exceeding a limit aborts loading entirely
+    ///
+    /// Otherwise files over the per-file limit get skipped and loading halts once the total limit
+    /// is reached
+    pub strict: bool,
+}
+
+/// Artificially slows down responses to test how a site behaves over a slow network
+///
+/// This is strictly for local testing and gets loudly warned about on startup
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Throttle {
+    /// A fixed delay in milliseconds before each response gets sent
+    pub latency_ms: u64,
+    /// Caps how fast response bodies get sent. Left unset bodies are sent as fast as possible
+    pub bytes_per_sec: Option<NonZeroU64>,
+}
+
+/// Which caches are allowed to store responses
+///
+/// Path globs are checked in order with the first match winning, then the content type, and then
+/// finally the default
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheScopes {
+    pub default: CacheScope,
+    pub types: BTreeMap<ContentType, CacheScope>,
+    pub paths: Vec<PathCacheScope>,
+}
+
+impl CacheScopes {
+    pub fn resolve(&self, rel_path: &str, ty: &ContentType) -> CacheScope {
+        self.paths
+            .iter()
+            .find(|path_scope| path_scope.globs.is_match(rel_path))
+            .map(|path_scope| path_scope.scope)
+            .or_else(|| self.types.get(ty).copied())
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathCacheScope {
+    pub globs: PathGlobs,
+    pub scope: CacheScope,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheScope {
+    /// Any cache may store the response (`Cache-Control: public`)
+    #[default]
+    Public,
+    /// Only the client's own cache may store the response (`Cache-Control: private`)
+    Private,
+    /// Nothing may store the response, and it's always sent in full without an e-tag
+    /// (`Cache-Control: no-store, no-cache, must-revalidate`)
+    NoStore,
+}
+
+/// What to do for `/` when the site has no root `index.html` (or configured root document)
+///
+/// Unlike [`Config::root_document`] this never takes over a `/` that would otherwise be served
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum RootFallback {
+    /// Temporarily redirect (`307 Temporary Redirect`) to a path like `/posts`, so that adding
+    /// an index later takes effect right away
+    Redirect(#[serde(deserialize_with = "deserialize_redirect_path")] String),
+    /// Serve a file (relative to the served directory)
+    Serve(String),
+}
+
+/// A `robots.txt` that gets generated when the site doesn't have its own
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Robots {
+    pub policy: RobotsPolicy,
+    /// A full URL to list as the `Sitemap:`
+    pub sitemap: Option<String>,
+}
+
+impl Robots {
+    pub fn to_robots_txt(&self) -> String {
+        let disallow = match self.policy {
+            RobotsPolicy::AllowAll => "",
+            RobotsPolicy::DisallowAll => " /",
+        };
+        let mut robots_txt = format!("User-agent: *\nDisallow:{disallow}\n");
+        if let Some(sitemap) = &self.sitemap {
+            robots_txt.push_str(&format!("Sitemap: {sitemap}\n"));
+        }
+        robots_txt
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RobotsPolicy {
+    /// Crawlers may crawl everything
+    #[default]
+    AllowAll,
+    /// Crawlers may crawl nothing
+    DisallowAll,
+}
+
+/// An at-a-glance summary of what got loaded that always gets logged once loading finishes
+///
+/// The file count, in-memory size, loading time, and count per content type are logged at `info`
+/// under the [`STARTUP_SUMMARY_TARGET`](crate::STARTUP_SUMMARY_TARGET) target, which the binary
+/// keeps enabled no matter the log level
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StartupSummary {
+    /// How many of the largest files (by in-memory size) to list. Left unset none are
+    pub largest_files: Option<usize>,
+}
+
+/// Paths that are known to only ever be requested by hostile scanners
+///
+/// Matching requests get a bare response with `status` before ever being routed and are left out
+/// of the access log to cut down on noise
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Probes {
+    pub paths: PathGlobs,
+    #[serde(deserialize_with = "deserialize_status")]
+    pub status: StatusCode,
+}
+
+impl Default for Probes {
+    fn default() -> Self {
+        Self {
+            paths: PathGlobs::default(),
+            status: StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+fn deserialize_status<'de, D: Deserializer<'de>>(deserializer: D) -> Result<StatusCode, D::Error> {
+    let code = u16::deserialize(deserializer)?;
+    StatusCode::from_u16(code).map_err(de::Error::custom)
+}
+
+fn deserialize_compressible_types<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<ContentType, bool>, D::Error> {
+    let types = BTreeMap::<ContentType, bool>::deserialize(deserializer)?;
+    match types
+        .iter()
+        .find(|(ty, compressible)| **compressible && !ty.is_compressible())
+    {
+        Some((ty, _)) => Err(de::Error::custom(format!(
+            "{} is a binary format that can't be compressed",
+            ty.name()
+        ))),
+        None => Ok(types),
+    }
+}
+
+fn deserialize_lgwin<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let lgwin = u32::deserialize(deserializer)?;
+    if (10..=24).contains(&lgwin) {
+        Ok(lgwin)
+    } else {
+        Err(de::Error::custom(format!(
+            "brotli's window must be from 10 to 24, got {lgwin}"
+        )))
+    }
+}
+
+fn deserialize_redirect_path<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let path = String::deserialize(deserializer)?;
+    if path.starts_with('/') {
+        Ok(path)
+    } else {
+        Err(de::Error::custom(format!(
+            "the redirect must be an absolute path starting with '/', got '{path}'"
+        )))
+    }
+}
+
+fn deserialize_statuses<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeSet<StatusCode>, D::Error> {
+    Vec::<u16>::deserialize(deserializer)?
+        .into_iter()
+        .map(|code| StatusCode::from_u16(code).map_err(de::Error::custom))
+        .collect()
+}
+
+/// A set of globs matched against paths relative to the root of the site
+///
+/// A leading `/` is optional for both the globs and the matched paths. `*` never matches across a
+/// `/`, so use `**` for that
+#[derive(Clone, Debug)]
+pub struct PathGlobs(GlobSet);
+
+impl Default for PathGlobs {
+    fn default() -> Self {
+        Self(GlobSet::empty())
+    }
+}
+
+impl PathGlobs {
+    pub fn new<I, S>(globs: I) -> Result<Self, globset::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut set = GlobSetBuilder::new();
+        for glob in globs {
+            let glob = glob.as_ref().trim_start_matches('/');
+            set.add(GlobBuilder::new(glob).literal_separator(true).build()?);
+        }
+        set.build().map(Self)
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        self.0.is_match(path.trim_start_matches('/'))
+    }
+}
+
+impl<'de> Deserialize<'de> for PathGlobs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let globs = Vec::<String>::deserialize(deserializer)?;
+        Self::new(globs).map_err(de::Error::custom)
+    }
+}
+
+/// A set of regexes matched against a request's `User-Agent`
+///
+/// A pattern matches anywhere in the header unless it's anchored with `^` and `$`
+#[derive(Clone, Debug, Default)]
+pub struct UserAgentPatterns(Option<Regex>);
+
+impl UserAgentPatterns {
+    pub fn new<I, S>(patterns: I) -> Result<Self, regex_lite::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        // there's no `RegexSet` in `regex-lite`, so all of the patterns get joined into one
+        let joined = patterns
+            .into_iter()
+            .map(|pattern| format!("(?:{})", pattern.as_ref()))
+            .collect::<Vec<_>>()
+            .join("|");
+        if joined.is_empty() {
+            Ok(Self::default())
+        } else {
+            Regex::new(&joined).map(|regex| Self(Some(regex)))
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn is_match(&self, user_agent: &str) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(user_agent))
+    }
+}
+
+impl<'de> Deserialize<'de> for UserAgentPatterns {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let patterns = Vec::<String>::deserialize(deserializer)?;
+        // validate each pattern on its own, so that a bad one can't get papered over by the join
+        for pattern in &patterns {
+            if let Err(err) = Regex::new(pattern) {
+                return Err(de::Error::custom(format!(
+                    "invalid user agent pattern {pattern:?}: {err}"
+                )));
+            }
+        }
+        Self::new(patterns).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLog {
+    pub format: LogFormat,
+    /// A file to append entries to. Entries get written to stdout when unset
+    pub path: Option<PathBuf>,
+}
+
+/// How each access log entry gets written
+///
+/// Both formats log the same fields
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// The Combined Log Format with the request duration in milliseconds tacked on the end
+    Combined,
+    /// Newline-delimited JSON objects
+    Json,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_toml() {
+        let config: Config = toml::from_str(
+            r#"
+            mount_prefix = "/blog/"
+            root_document = "home.html"
+            root_fallback = { redirect = "/posts" }
+            trailing_slash = "redirect-to-no-slash"
+            incompressible = ["/img/sprite.svg"]
+            encodings = ["br"]
+            status_page_dir = "errors"
+            status_pages = [404, 410]
+
+            [limits]
+            max_file_size = 1_000_000
+            strict = true
+
+            [access_log]
+            format = "json"
+
+            [cache_scope]
+            default = "private"
+            types = { html = "no-store" }
+            paths = [{ globs = ["members/**"], scope = "no-store" }]
+
+            [probes]
+            paths = ["/.env"]
+            status = 403
+
+            [startup_summary]
+            largest_files = 3
+
+            [robots]
+            policy = "disallow-all"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.normalized_mount_prefix(), "/blog");
+        assert_eq!(config.root_document.as_deref(), Some("home.html"));
+        assert_eq!(
+            config.root_fallback,
+            Some(RootFallback::Redirect("/posts".into()))
+        );
+        assert_eq!(config.trailing_slash, TrailingSlash::RedirectToNoSlash);
+        assert!(config.incompressible.is_match("img/sprite.svg"));
+        assert_eq!(config.encodings, [Encoding::Brotli]);
+        assert_eq!(config.status_page_dir, "errors");
+        assert_eq!(
+            config.status_pages,
+            [StatusCode::NOT_FOUND, StatusCode::GONE].into()
+        );
+        assert_eq!(config.limits.max_file_size, Some(1_000_000));
+        assert!(config.limits.strict);
+        assert_eq!(config.access_log.unwrap().format, LogFormat::Json);
+        assert_eq!(
+            config
+                .cache_scope
+                .resolve("members/index.html", &ContentType::Css),
+            CacheScope::NoStore
+        );
+        assert_eq!(
+            config.cache_scope.resolve("index.html", &ContentType::Html),
+            CacheScope::NoStore
+        );
+        assert_eq!(
+            config.cache_scope.resolve("index.css", &ContentType::Css),
+            CacheScope::Private
+        );
+        assert_eq!(config.probes.status, StatusCode::FORBIDDEN);
+        assert_eq!(config.startup_summary.largest_files, Some(3));
+        assert_eq!(config.robots.unwrap().policy, RobotsPolicy::DisallowAll);
+        // and unset fields fall back to their defaults
+        assert_eq!(config.retry_after_secs, 5);
+    }
+
+    #[test]
+    fn extra_types() {
+        let config: Config = toml::from_str(
+            r#"
+            [extra_types]
+            vtt = { mime = "text/vtt", compressible = true }
+            html = { mime = "text/x-not-html" }
+            png = { mime = "image/x-png", override = true }
+            "#,
+        )
+        .unwrap();
+        let custom = |mime, compressible| {
+            Some(ContentType::Custom(CustomType {
+                mime: HeaderValue::from_static(mime),
+                compressible,
+            }))
+        };
+        assert_eq!(
+            config.content_type_for("captions/en.vtt"),
+            custom("text/vtt", true)
+        );
+        assert_eq!(
+            config.content_type_for("index.html"),
+            Some(ContentType::Html)
+        );
+        assert_eq!(
+            config.content_type_for("img/favicon.png"),
+            custom("image/x-png", false)
+        );
+        assert_eq!(config.content_type_for(".vtt"), None);
+
+        // control characters can't be in a header at all, and non-ASCII can't be in a content type
+        for mime in ["text/vtt\\n", "text/é"] {
+            let toml = format!("extra_types.vtt.mime = \"{mime}\"");
+            let err = toml::from_str::<Config>(&toml).unwrap_err();
+            assert!(err.to_string().contains("invalid content type"), "{err}");
+        }
+    }
+
+    #[test]
+    fn compressible_types() {
+        let config: Config = toml::from_str("compressible_types = { html = false }").unwrap();
+        assert!(config.compression_for("index.html").encodings.is_empty());
+        assert!(!config.compression_for("robots.txt").encodings.is_empty());
+
+        let err = toml::from_str::<Config>("compressible_types = { png = true }").unwrap_err();
+        assert!(err.to_string().contains("can't be compressed"), "{err}");
+    }
+
+    #[test]
+    fn rejects_invalid_brotli_window() {
+        let err = toml::from_str::<Config>("brotli_lgwin = 25").unwrap_err();
+        assert!(err.to_string().contains("from 10 to 24"), "{err}");
+        let config: Config = toml::from_str("brotli_lgwin = 18").unwrap();
+        assert_eq!(config.brotli_lgwin, 18);
+    }
+
+    #[test]
+    fn rejects_relative_redirect() {
+        let err = toml::from_str::<Config>("root_fallback = { redirect = \"posts\" }").unwrap_err();
+        assert!(err.to_string().contains("starting with '/'"), "{err}");
+    }
+
+    #[test]
+    fn user_agent_patterns() {
+        let config: Config =
+            toml::from_str(r#"identity_user_agents = ["MSIE [56]\\.", "^AncientBot/"]"#).unwrap();
+        let patterns = config.identity_user_agents;
+        assert!(patterns.is_match("Mozilla/4.0 (compatible; MSIE 6.0; Windows NT 5.1)"));
+        assert!(patterns.is_match("AncientBot/1.2"));
+        assert!(!patterns.is_match("NotAncientBot/1.2"));
+        assert!(!patterns.is_match("curl/8.0"));
+        assert!(!UserAgentPatterns::default().is_match("curl/8.0"));
+
+        // an unbalanced group can't hide behind the other patterns when they're joined
+        let err = toml::from_str::<Config>(r#"identity_user_agents = ["a)|(b"]"#).unwrap_err();
+        assert!(
+            err.to_string().contains("invalid user agent pattern"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let err = toml::from_str::<Config>("root_documnet = \"home.html\"").unwrap_err();
+        assert!(err.to_string().contains("unknown field"), "{err}");
+    }
+}
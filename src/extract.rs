@@ -1,15 +1,21 @@
-use std::{convert::Infallible, str::FromStr};
+use std::{convert::Infallible, ops, str::FromStr, time::SystemTime};
+
+use crate::util::parse_http_date;
 
 use axum::{
     extract::{FromRequestParts, OptionalFromRequestParts},
     http::{HeaderValue, header, request},
 };
+use serde::Deserialize;
 
-#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
 pub enum Encoding {
     #[default]
+    #[serde(rename = "identity")]
     Identity,
+    #[serde(rename = "gzip")]
     Gzip,
+    #[serde(rename = "br")]
     Brotli,
 }
 
@@ -40,7 +46,27 @@ impl FromStr for Encoding {
     }
 }
 
-impl<S> FromRequestParts<S> for Encoding
+/// The encodings that the client accepts in order of preference
+#[derive(Clone, Copy, Default)]
+pub struct AcceptEncoding {
+    // there are only a few encodings, so a fixed-size array easily holds all of them
+    preferred: [Option<Encoding>; 3],
+}
+
+impl AcceptEncoding {
+    /// Picks the client's most preferred encoding out of the available ones
+    ///
+    /// Identity is always available
+    pub fn negotiate(self, is_available: impl Fn(Encoding) -> bool) -> Encoding {
+        self.preferred
+            .into_iter()
+            .flatten()
+            .find(|&encoding| encoding == Encoding::Identity || is_available(encoding))
+            .unwrap_or_default()
+    }
+}
+
+impl<S> FromRequestParts<S> for AcceptEncoding
 where
     S: Send + Sync,
 {
@@ -50,10 +76,10 @@ where
         parts: &mut request::Parts,
         _: &S,
     ) -> Result<Self, Self::Rejection> {
-        fn from_req_parts(parts: &request::Parts) -> Option<Encoding> {
+        fn from_req_parts(parts: &request::Parts) -> Option<AcceptEncoding> {
             let accept_encoding = parts.headers.get(header::ACCEPT_ENCODING)?;
             let accept_encoding = accept_encoding.to_str().ok()?;
-            accept_encoding
+            let encodings = accept_encoding
                 .split(',')
                 .filter_map(|chunk| {
                     let trimmed = chunk.trim();
@@ -63,12 +89,23 @@ where
                         None => Some(trimmed),
                     }
                 })
-                .filter_map(|encoding| encoding.parse().ok())
-                .next()
+                .filter_map(|encoding| encoding.parse().ok());
+
+            let mut preferred = [None; 3];
+            let mut len = 0;
+            for encoding in encodings {
+                if len == preferred.len() {
+                    break;
+                } else if !preferred.contains(&Some(encoding)) {
+                    preferred[len] = Some(encoding);
+                    len += 1;
+                }
+            }
+            Some(AcceptEncoding { preferred })
         }
 
-        let encoding = from_req_parts(&*parts).unwrap_or_default();
-        Ok(encoding)
+        let accept_encoding = from_req_parts(&*parts).unwrap_or_default();
+        Ok(accept_encoding)
     }
 }
 
@@ -76,6 +113,26 @@ where
 // `headers::IfNoneMatch` impl
 pub struct IfNoneMatch(pub String);
 
+impl IfNoneMatch {
+    /// Whether any of the listed tags matches `e_tag`
+    ///
+    /// This uses weak comparison as `If-None-Match` calls for, so `W/"abc"` and `"abc"` match
+    pub fn matches(&self, e_tag: &HeaderValue) -> bool {
+        fn opaque_tag(tag: &str) -> &str {
+            let tag = tag.trim();
+            tag.strip_prefix("W/").unwrap_or(tag)
+        }
+
+        let Ok(e_tag) = e_tag.to_str() else {
+            return false;
+        };
+        let e_tag = opaque_tag(e_tag);
+        self.0
+            .split(',')
+            .any(|tag| tag.trim() == "*" || opaque_tag(tag) == e_tag)
+    }
+}
+
 impl<S> OptionalFromRequestParts<S> for IfNoneMatch
 where
     S: Send + Sync,
@@ -94,3 +151,166 @@ where
         Ok(maybe_tag)
     }
 }
+
+/// The date from an `If-Modified-Since` header
+///
+/// An unparseable date is ignored as if the header wasn't sent at all
+#[derive(Clone, Copy)]
+pub struct IfModifiedSince(pub SystemTime);
+
+impl<S> OptionalFromRequestParts<S> for IfModifiedSince
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut request::Parts,
+        _: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        let maybe_date = parts
+            .headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(parse_http_date)
+            .map(Self);
+        Ok(maybe_date)
+    }
+}
+
+/// A single byte range from the `Range` header along with any `If-Range` precondition
+///
+/// Multiple ranges aren't supported, so those get ignored like any other unparseable range, which
+/// means the full content gets sent instead
+#[derive(Clone)]
+pub struct Range {
+    pub spec: RangeSpec,
+    pub if_range: Option<HeaderValue>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangeSpec {
+    /// `bytes=<START>-`
+    From(u64),
+    /// `bytes=<START>-<END>` where the end is inclusive
+    FromTo(u64, u64),
+    /// `bytes=-<LEN>`
+    Last(u64),
+}
+
+impl RangeSpec {
+    fn parse(s: &str) -> Option<Self> {
+        let (start, end) = s.strip_prefix("bytes=")?.trim().split_once('-')?;
+        let parse_num = |num: &str| {
+            // `u64::from_str()` also takes a leading `+`
+            let is_digits = !num.is_empty() && num.bytes().all(|b| b.is_ascii_digit());
+            is_digits.then(|| num.parse().ok()).flatten()
+        };
+        let spec = match (start, end) {
+            ("", len) => Self::Last(parse_num(len)?),
+            (start, "") => Self::From(parse_num(start)?),
+            (start, end) => {
+                let (start, end) = (parse_num(start)?, parse_num(end)?);
+                if start > end {
+                    return None;
+                }
+                Self::FromTo(start, end)
+            }
+        };
+        Some(spec)
+    }
+
+    /// The range of content that's `len` bytes long, or `None` when it's unsatisfiable
+    pub fn resolve(self, len: u64) -> Option<ops::Range<u64>> {
+        let range = match self {
+            Self::From(start) => start..len,
+            Self::FromTo(start, end) => start..len.min(end.saturating_add(1)),
+            Self::Last(suffix_len) => len.saturating_sub(suffix_len)..len,
+        };
+        (!range.is_empty()).then_some(range)
+    }
+}
+
+impl<S> OptionalFromRequestParts<S> for Range
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut request::Parts,
+        _: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        let maybe_range = parts
+            .headers
+            .get(header::RANGE)
+            .and_then(|range| range.to_str().ok())
+            .and_then(RangeSpec::parse)
+            .map(|spec| Self {
+                spec,
+                if_range: parts.headers.get(header::IF_RANGE).cloned(),
+            });
+        Ok(maybe_range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_spec() {
+        let table = [
+            ("bytes=0-3", Some(0..4)),
+            ("bytes=2-", Some(2..10)),
+            ("bytes=-4", Some(6..10)),
+            ("bytes=8-100", Some(8..10)),
+            ("bytes=-100", Some(0..10)),
+            ("bytes=10-", None),
+            ("bytes=-0", None),
+        ];
+        for (range, expected) in table {
+            let spec = RangeSpec::parse(range).unwrap();
+            assert_eq!(spec.resolve(10), expected, "{range}");
+        }
+
+        for unparseable in [
+            "bytes=3-2",
+            "bytes=0-1,4-5",
+            "bytes=-",
+            "bytes=+1-2",
+            "items=0-1",
+        ] {
+            assert_eq!(RangeSpec::parse(unparseable), None, "{unparseable}");
+        }
+    }
+
+    #[test]
+    fn if_none_match_weak_comparison() {
+        let strong = HeaderValue::from_static("\"abc\"");
+        let weak = HeaderValue::from_static("W/\"abc\"");
+        let table = [
+            ("\"abc\"", true),
+            ("W/\"abc\"", true),
+            ("\"xyz\", W/\"abc\"", true),
+            ("*", true),
+            ("\"xyz\"", false),
+            ("\"abc", false),
+        ];
+        for (if_none_match, expected) in table {
+            let if_none_match = IfNoneMatch(if_none_match.to_owned());
+            assert_eq!(
+                if_none_match.matches(&strong),
+                expected,
+                "{}",
+                if_none_match.0
+            );
+            assert_eq!(
+                if_none_match.matches(&weak),
+                expected,
+                "{}",
+                if_none_match.0
+            );
+        }
+    }
+}
@@ -1,8 +1,16 @@
-use std::{fs, mem, path::Path};
+use std::{
+    fs,
+    hash::Hasher,
+    io, mem,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
-    extract::{Encoding, IfNoneMatch},
-    util::TotalSize,
+    cache,
+    config::CacheScope,
+    extract::{AcceptEncoding, Encoding, IfModifiedSince, IfNoneMatch, Range, RangeSpec},
+    util::{TotalSize, disp},
 };
 
 use axum::{
@@ -10,47 +18,175 @@ use axum::{
     http::{HeaderMap, HeaderValue, StatusCode, header},
     response::Response,
 };
+use serde::Deserialize;
 use twox_hash::XxHash64;
 
 #[derive(Clone)]
 pub struct ServedFile {
     e_tag: HeaderValue,
+    /// Truncated to whole seconds to match what an HTTP-date can hold
+    last_modified: Option<SystemTime>,
     ty: ContentType,
+    cache_scope: CacheScope,
     file: File,
 }
 
 impl TotalSize for ServedFile {
     fn total_size(&self) -> usize {
-        let ServedFile { e_tag, ty, file } = self;
-        e_tag.total_size() + ty.total_size() + file.total_size()
+        let ServedFile {
+            e_tag,
+            last_modified,
+            ty,
+            cache_scope,
+            file,
+        } = self;
+        e_tag.total_size()
+            + mem::size_of_val(last_modified)
+            + ty.total_size()
+            + mem::size_of_val(cache_scope)
+            + file.total_size()
     }
 }
 
 impl ServedFile {
-    pub fn load(path: &Path) -> Option<Self> {
-        let ext = path.extension()?.to_str()?;
-        let ty = ContentType::from_file_ext(ext)?;
-
-        let contents = fs::read(path).ok()?;
-        let e_tag = {
-            const ARBITRARY_SEED: u64 = 0xc0ffee;
-            let hash = XxHash64::oneshot(ARBITRARY_SEED, &contents);
-            // format as a strong e-tag as we're constructing it off the bytes themselves
-            let value = format!("\"{hash:x}\"");
-            value.parse().expect("the format is a valid e-tag")
+    const E_TAG_SEED: u64 = 0xc0ffee;
+
+    /// Loads the file at `path` that gets served as `ty`
+    ///
+    /// Compressible content gets stored compressed according to `compression`. Files that are at
+    /// least `weak_e_tag_min_size` bytes get a cheaper weak e-tag
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] when compressible content isn't valid UTF-8
+    pub fn load(
+        path: &Path,
+        ty: ContentType,
+        compression: CompressionConfig<'_>,
+        weak_e_tag_min_size: Option<u64>,
+    ) -> io::Result<Self> {
+        let contents = fs::read(path)?;
+        let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        let last_modified = modified.map(|modified| {
+            let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+            UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs())
+        });
+        // without a modification time there's nothing to notice changes between the samples by, so
+        // those files fall back to hashing everything
+        let weak_modified = modified
+            .filter(|_| weak_e_tag_min_size.is_some_and(|min| contents.len() as u64 >= min));
+        let e_tag = if let Some(modified) = weak_modified {
+            // hashing all of a huge file is slow, so only sample the parts that are most likely to
+            // change along with the metadata. that's not a guarantee of byte-for-byte equality, so
+            // it's formatted as a weak e-tag
+            const SAMPLE_SIZE: usize = 64 * 1_024;
+            let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let mut hasher = XxHash64::with_seed(Self::E_TAG_SEED);
+            hasher.write_usize(contents.len());
+            hasher.write_u128(since_epoch.as_nanos());
+            hasher.write(&contents[..SAMPLE_SIZE.min(contents.len())]);
+            hasher.write(&contents[contents.len().saturating_sub(SAMPLE_SIZE)..]);
+            let e_tag = format!("W/\"{:x}\"", hasher.finish());
+            e_tag.parse().expect("the format is a valid e-tag")
+        } else {
+            Self::strong_e_tag(&contents)
         };
+        let served_file = Self::new(contents, e_tag, ty.clone(), compression).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} content isn't valid UTF-8", ty.name()),
+            )
+        })?;
+        Ok(Self {
+            last_modified,
+            ..served_file
+        })
+    }
+
+    /// Like [`ServedFile::load()`], but for contents generated in memory
+    ///
+    /// The contents must be valid UTF-8 when `ty` is text
+    pub fn from_contents(
+        contents: Vec<u8>,
+        ty: ContentType,
+        compression: CompressionConfig<'_>,
+    ) -> Self {
+        let e_tag = Self::strong_e_tag(&contents);
+        Self::new(contents, e_tag, ty, compression).expect("text contents are valid UTF-8")
+    }
 
-        let file = if ty.is_compressible() {
+    fn strong_e_tag(contents: &[u8]) -> HeaderValue {
+        let hash = XxHash64::oneshot(Self::E_TAG_SEED, contents);
+        // format as a strong e-tag as we're constructing it off the bytes themselves
+        let e_tag = format!("\"{hash:x}\"");
+        e_tag.parse().expect("the format is a valid e-tag")
+    }
+
+    fn new(
+        contents: Vec<u8>,
+        e_tag: HeaderValue,
+        ty: ContentType,
+        compression: CompressionConfig<'_>,
+    ) -> Option<Self> {
+        // compressing an empty file would only leave us with a non-empty body of pure framing, so
+        // empty files are always served as is
+        let compress = !contents.is_empty()
+            && compression
+                .encodings
+                .iter()
+                .any(|&encoding| encoding != Encoding::Identity);
+        let file = if compress && ty.is_compressible() {
             let contents = String::from_utf8(contents).ok()?;
-            File::Text(contents.into())
+            File::Text(TextFile::new(contents, compression))
         } else {
             File::Data(contents.into())
         };
 
-        Some(Self { e_tag, ty, file })
+        Some(Self {
+            e_tag,
+            last_modified: None,
+            ty,
+            cache_scope: CacheScope::default(),
+            file,
+        })
+    }
+
+    pub fn content_type(&self) -> &ContentType {
+        &self.ty
+    }
+
+    pub fn cache_scope(&self) -> CacheScope {
+        self.cache_scope
+    }
+
+    pub fn with_cache_scope(self, cache_scope: CacheScope) -> Self {
+        Self {
+            cache_scope,
+            ..self
+        }
     }
 
-    pub fn to_response(&self, encoding: Encoding, if_none_match: Option<IfNoneMatch>) -> Response {
+    /// The compression ratio for files that are stored compressed
+    ///
+    /// This is the brotli ratio when stored with brotli, and the gzip ratio otherwise
+    pub fn compression_ratio(&self) -> Option<f32> {
+        match &self.file {
+            File::Data(_) => None,
+            File::Text(text_file) => {
+                let compressed = text_file
+                    .br_compressed
+                    .as_ref()
+                    .or(text_file.gz_compressed.as_ref())?;
+                Some(compressed.len() as f32 / text_file.contents.len() as f32)
+            }
+        }
+    }
+
+    pub fn to_response(
+        &self,
+        accept_encoding: AcceptEncoding,
+        if_none_match: Option<IfNoneMatch>,
+        if_modified_since: Option<IfModifiedSince>,
+        range: Option<Range>,
+    ) -> Response {
         const SERVER: HeaderValue = HeaderValue::from_static(concat!(
             env!("CARGO_PKG_NAME"),
             " ",
@@ -58,36 +194,126 @@ impl ServedFile {
         ));
         let mut builder = Response::builder()
             .header(header::SERVER, SERVER)
-            .header(header::CONTENT_TYPE, self.ty.into_header_value())
-            // TODO: set this based on content type?
-            .header(header::CACHE_CONTROL, "max-age=300");
-
-        // handle etag content revalidation
-        if if_none_match.is_some_and(|client_tag| client_tag.0 == self.e_tag) {
+            .header(header::CONTENT_TYPE, self.ty.to_header_value())
+            .header(header::CACHE_CONTROL, self.cache_scope.into_header_value());
+
+        // responses that must never be cached are always sent in full and without an e-tag, so
+        // that there's nothing to revalidate with
+        let no_store = self.cache_scope == CacheScope::NoStore;
+
+        // handle content revalidation
+        let not_modified = match (if_none_match, if_modified_since) {
+            // the e-tag is more precise, so it takes precedence when both get sent
+            (Some(client_tag), _) => client_tag.matches(&self.e_tag),
+            (None, Some(IfModifiedSince(since))) => self
+                .last_modified
+                .is_some_and(|last_modified| last_modified <= since),
+            (None, None) => false,
+        };
+        if !no_store && not_modified {
+            // caches update their stored response from the `304`, so it has to carry the same
+            // validator and `Vary` that the full response would have
+            builder = builder.header(header::ETAG, self.e_tag.clone());
+            if let File::Text(_) = &self.file {
+                builder = builder.header(header::VARY, header::ACCEPT_ENCODING);
+            }
             builder
                 .status(StatusCode::NOT_MODIFIED)
                 .body(Body::empty())
                 .unwrap()
         } else {
+            let mut status = StatusCode::OK;
             let bytes = match &self.file {
-                File::Data(data_file) => data_file.0.clone(),
+                // ranges only make sense for content that's always sent as is
+                File::Data(data_file) => {
+                    builder = builder.header(header::ACCEPT_RANGES, "bytes");
+                    match range.filter(|range| self.range_applies(range)) {
+                        Some(Range { spec, .. }) => {
+                            let (range_status, content_range, bytes) =
+                                select_range(&data_file.0, spec);
+                            status = range_status;
+                            builder = builder.header(header::CONTENT_RANGE, content_range);
+                            bytes
+                        }
+                        None => data_file.0.clone(),
+                    }
+                }
                 File::Text(text_file) => {
+                    let encoding = accept_encoding.negotiate(|encoding| text_file.has(encoding));
                     text_file.setup_headers(builder.headers_mut().unwrap(), encoding);
                     text_file.select_body_bytes(encoding)
                 }
             };
 
-            builder = builder
-                .header(header::ETAG, self.e_tag.clone())
-                // `axum` automatically sets the content length for us, but we explicitly set it
-                // here, so that our custom middleware can see it
-                .header(header::CONTENT_LENGTH, bytes.len());
+            if !no_store {
+                builder = builder.header(header::ETAG, self.e_tag.clone());
+                if let Some(last_modified) = self.last_modified {
+                    let last_modified = disp::HttpDate(last_modified).to_string();
+                    builder = builder.header(header::LAST_MODIFIED, last_modified);
+                }
+            }
+            // `axum` automatically sets the content length for us, but we explicitly set it here,
+            // so that our custom middleware can see it
+            builder = builder.header(header::CONTENT_LENGTH, bytes.len());
+
+            builder.status(status).body(bytes.into()).unwrap()
+        }
+    }
+
+    /// Whether the `If-Range` (if any) still matches, so that the range can be sent
+    ///
+    /// `If-Range` needs a strong match, so only strong e-tags can match. Dates never do, which is
+    /// always safe since the full content gets sent instead
+    fn range_applies(&self, range: &Range) -> bool {
+        match &range.if_range {
+            None => true,
+            Some(if_range) => {
+                let is_strong = !self.e_tag.as_bytes().starts_with(b"W/");
+                let no_store = self.cache_scope == CacheScope::NoStore;
+                is_strong && !no_store && if_range == self.e_tag
+            }
+        }
+    }
+}
 
-            builder.body(bytes.into()).unwrap()
+/// Narrows `bytes` down to `spec` returning the status and `Content-Range` to send along with it
+fn select_range(bytes: &Bytes, spec: RangeSpec) -> (StatusCode, String, Bytes) {
+    let len = bytes.len() as u64;
+    match spec.resolve(len) {
+        Some(range) => {
+            let content_range = format!("bytes {}-{}/{len}", range.start, range.end - 1);
+            let bytes = bytes.slice(range.start as usize..range.end as usize);
+            (StatusCode::PARTIAL_CONTENT, content_range, bytes)
+        }
+        None => {
+            let content_range = format!("bytes */{len}");
+            (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                content_range,
+                Bytes::new(),
+            )
         }
     }
 }
 
+/// How compressible files get stored
+#[derive(Clone, Copy)]
+pub struct CompressionConfig<'a> {
+    /// Each of the non-identity encodings gets stored
+    pub encodings: &'a [Encoding],
+    /// The base 2 log of brotli's window size
+    pub brotli_lgwin: u32,
+    /// A directory to persist compressed contents in across restarts
+    pub cache_dir: Option<&'a Path>,
+    /// The compressed to uncompressed size ratio above which a warning gets logged
+    pub poor_ratio: f32,
+}
+
+impl CompressionConfig<'_> {
+    pub const DEFAULT_BROTLI_LGWIN: u32 = 22;
+    pub const DEFAULT_POOR_RATIO: f32 = 0.9;
+}
+
 // TODO: switch this to automaitcally try compressing and bail out if the size isn't better
 #[derive(Clone)]
 enum File {
@@ -131,11 +357,11 @@ impl From<Vec<u8>> for DataFile {
     }
 }
 
-// NOTE: UTF-8 is validated before construction
+// NOTE: UTF-8 is validated before construction and the contents are never empty
 #[derive(Clone)]
 struct TextFile {
-    gz_compressed: Bytes,
-    br_compressed: Bytes,
+    gz_compressed: Option<Bytes>,
+    br_compressed: Option<Bytes>,
     contents: Bytes,
 }
 
@@ -152,38 +378,81 @@ impl TotalSize for TextFile {
 
 impl TextFile {
     fn setup_headers(&self, headers: &mut HeaderMap, encoding: Encoding) {
-        // include the encodings we support for this entity no matter what
-        headers.insert(header::ACCEPT_ENCODING, Encoding::ALL_ENCODINGS);
+        // the selected body depends on the request's `Accept-Encoding` even when we fall back to
+        // identity, so caches need to key off of it either way
+        headers.insert(header::VARY, header::ACCEPT_ENCODING.into());
+        // hint at which encodings this entity is available in (RFC 7694). this is _not_ an echo of
+        // the request header, and it's only useful when there's an actual choice to be made
+        if self.stored_encodings() > 1 {
+            headers.insert(header::ACCEPT_ENCODING, Encoding::ALL_ENCODINGS);
+        }
 
         // setup headers for our content encoding
         if let Some(content_encoding) = encoding.into_content_encoding_value() {
-            headers.insert(header::VARY, header::ACCEPT_ENCODING.into());
             headers.insert(header::CONTENT_ENCODING, content_encoding);
         }
     }
 
-    fn select_body_bytes(&self, encoding: Encoding) -> Bytes {
+    /// How many compressed encodings this file is stored with
+    fn stored_encodings(&self) -> usize {
+        [&self.gz_compressed, &self.br_compressed]
+            .into_iter()
+            .flatten()
+            .count()
+    }
+
+    /// Whether the file is stored with `encoding`
+    fn has(&self, encoding: Encoding) -> bool {
         match encoding {
-            Encoding::Gzip => self.gz_compressed.clone(),
-            Encoding::Brotli => self.br_compressed.clone(),
-            Encoding::Identity => self.contents.clone(),
+            Encoding::Gzip => self.gz_compressed.is_some(),
+            Encoding::Brotli => self.br_compressed.is_some(),
+            Encoding::Identity => true,
         }
     }
-}
 
-impl From<String> for TextFile {
-    fn from(contents: String) -> Self {
-        fn check_compression_ratio(source: &[u8], compressed: &[u8]) {
-            let ratio = compressed.len() as f32 / source.len() as f32;
-            if ratio > 0.9 {
+    // NOTE: `encoding` must be one that the file `has()`
+    fn select_body_bytes(&self, encoding: Encoding) -> Bytes {
+        let bytes = match encoding {
+            Encoding::Gzip => self.gz_compressed.as_ref(),
+            Encoding::Brotli => self.br_compressed.as_ref(),
+            Encoding::Identity => Some(&self.contents),
+        };
+        bytes.expect("negotiated encodings are stored").clone()
+    }
+
+    /// Only the selected encodings get compressed and stored alongside the uncompressed contents
+    fn new(contents: String, compression: CompressionConfig<'_>) -> Self {
+        let CompressionConfig {
+            encodings,
+            brotli_lgwin,
+            cache_dir,
+            poor_ratio,
+        } = compression;
+        // entries are keyed off of the contents along with anything else that changes the output
+        let content_hash =
+            cache_dir.map(|_| XxHash64::oneshot(ServedFile::E_TAG_SEED, contents.as_bytes()));
+        let compress = |key: String, compress: &dyn Fn(&[u8]) -> Vec<u8>| -> Bytes {
+            let compressed = match cache_dir.zip(content_hash) {
+                Some((cache_dir, hash)) => {
+                    let key = format!("{hash:016x}.{key}");
+                    cache::get_or_insert_with(cache_dir, &key, || compress(contents.as_bytes()))
+                }
+                None => compress(contents.as_bytes()),
+            };
+            let ratio = compressed.len() as f32 / contents.len() as f32;
+            if ratio > poor_ratio {
                 tracing::warn!(ratio, "Poor compression");
             }
-        }
-        let gz_compressed: Bytes = gz_compress(contents.as_bytes()).into();
-        let br_compressed: Bytes = br_compress(contents.as_bytes()).into();
+            compressed.into()
+        };
+        let gz_compressed = encodings
+            .contains(&Encoding::Gzip)
+            .then(|| compress("gz".into(), &gz_compress));
+        let br_compressed = encodings.contains(&Encoding::Brotli).then(|| {
+            let br_compress = |bytes: &[u8]| br_compress(bytes, brotli_lgwin);
+            compress(format!("lgwin{brotli_lgwin}.br"), &br_compress)
+        });
         let contents: Bytes = contents.into();
-        check_compression_ratio(&contents, &gz_compressed);
-        check_compression_ratio(&contents, &br_compressed);
         Self {
             gz_compressed,
             br_compressed,
@@ -202,33 +471,51 @@ fn gz_compress(bytes: &[u8]) -> Vec<u8> {
     encoder.finish().unwrap()
 }
 
-fn br_compress(bytes: &[u8]) -> Vec<u8> {
+fn br_compress(bytes: &[u8], lgwin: u32) -> Vec<u8> {
     use std::io::prelude::*;
 
     use brotli::CompressorWriter;
 
     const BUFFER_SIZE: usize = 4_096;
     const BEST_QUALITY: u32 = 11;
-    const LGWIN: u32 = 22;
 
     let output = Vec::new();
-    let mut encoder = CompressorWriter::new(output, BUFFER_SIZE, BEST_QUALITY, LGWIN);
+    let mut encoder = CompressorWriter::new(output, BUFFER_SIZE, BEST_QUALITY, lgwin);
     encoder.write_all(bytes).unwrap();
     encoder.flush().unwrap();
     encoder.into_inner()
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ContentType {
     Html,
     Js,
     Svg,
     Css,
     Xml,
+    Rss,
+    Atom,
     Txt,
+    Json,
     Woff,
     Woff2,
     Png,
+    Jpeg,
+    Gif,
+    Ico,
+    /// A type added through [`Config::extra_types`](crate::Config::extra_types)
+    #[serde(skip)]
+    Custom(CustomType),
+}
+
+/// A content type from the config
+///
+/// Compressible types must be UTF-8 text
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CustomType {
+    pub mime: HeaderValue,
+    pub compressible: bool,
 }
 
 impl TotalSize for ContentType {
@@ -237,28 +524,86 @@ impl TotalSize for ContentType {
     }
 }
 
-impl ContentType {
+impl CacheScope {
     const fn into_header_value(self) -> HeaderValue {
+        match self {
+            Self::Public => HeaderValue::from_static("public, max-age=300"),
+            Self::Private => HeaderValue::from_static("private, max-age=300"),
+            Self::NoStore => HeaderValue::from_static("no-store, no-cache, must-revalidate"),
+        }
+    }
+}
+
+impl ContentType {
+    // NOTE: every type that `is_text()` declares a charset and nothing else does, except for JSON
+    // which is always UTF-8 and has no charset parameter and custom types which are used as is.
+    // this is checked in the tests below
+    fn to_header_value(&self) -> HeaderValue {
         match self {
             ContentType::Html => HeaderValue::from_static("text/html; charset=utf-8"),
             ContentType::Js => HeaderValue::from_static("application/javascript; charset=utf-8"),
-            ContentType::Svg => HeaderValue::from_static("image/svg+xml"),
+            ContentType::Svg => HeaderValue::from_static("image/svg+xml; charset=utf-8"),
             ContentType::Css => HeaderValue::from_static("text/css; charset=utf-8"),
-            ContentType::Xml => HeaderValue::from_static("application/xml"),
-            ContentType::Txt => HeaderValue::from_static("text/plain"),
+            ContentType::Xml => HeaderValue::from_static("application/xml; charset=utf-8"),
+            ContentType::Rss => HeaderValue::from_static("application/rss+xml; charset=utf-8"),
+            ContentType::Atom => HeaderValue::from_static("application/atom+xml; charset=utf-8"),
+            ContentType::Txt => HeaderValue::from_static("text/plain; charset=utf-8"),
+            ContentType::Json => HeaderValue::from_static("application/json"),
             ContentType::Woff => HeaderValue::from_static("font/woff"),
             ContentType::Woff2 => HeaderValue::from_static("font/woff2"),
             ContentType::Png => HeaderValue::from_static("image/png"),
+            ContentType::Jpeg => HeaderValue::from_static("image/jpeg"),
+            ContentType::Gif => HeaderValue::from_static("image/gif"),
+            ContentType::Ico => HeaderValue::from_static("image/x-icon"),
+            ContentType::Custom(custom) => custom.mime.clone(),
         }
     }
 
-    fn is_compressible(self) -> bool {
+    /// The name used for the type in the config
+    pub fn name(&self) -> &str {
         match self {
-            Self::Html | Self::Js | Self::Svg | Self::Css | Self::Xml | Self::Txt => true,
-            Self::Woff | Self::Woff2 | Self::Png => false,
+            Self::Html => "html",
+            Self::Js => "js",
+            Self::Svg => "svg",
+            Self::Css => "css",
+            Self::Xml => "xml",
+            Self::Rss => "rss",
+            Self::Atom => "atom",
+            Self::Txt => "txt",
+            Self::Json => "json",
+            Self::Woff => "woff",
+            Self::Woff2 => "woff2",
+            Self::Png => "png",
+            Self::Jpeg => "jpeg",
+            Self::Gif => "gif",
+            Self::Ico => "ico",
+            // only visible ASCII is allowed in the config, but ones built in code may not be
+            Self::Custom(custom) => custom.mime.to_str().unwrap_or("custom"),
         }
     }
 
+    /// Whether the content is (UTF-8) text instead of arbitrary binary data
+    const fn is_text(&self) -> bool {
+        match self {
+            Self::Html
+            | Self::Js
+            | Self::Svg
+            | Self::Css
+            | Self::Xml
+            | Self::Rss
+            | Self::Atom
+            | Self::Txt
+            | Self::Json => true,
+            Self::Woff | Self::Woff2 | Self::Png | Self::Jpeg | Self::Gif | Self::Ico => false,
+            Self::Custom(custom) => custom.compressible,
+        }
+    }
+
+    pub(crate) fn is_compressible(&self) -> bool {
+        // all of our binary formats are already compressed internally
+        self.is_text()
+    }
+
     pub fn from_file_ext(ext: &str) -> Option<Self> {
         let ty = match ext {
             "html" => Self::Html,
@@ -266,12 +611,185 @@ impl ContentType {
             "svg" => Self::Svg,
             "css" => Self::Css,
             "xml" => Self::Xml,
+            "rss" => Self::Rss,
+            "atom" => Self::Atom,
             "txt" => Self::Txt,
+            "json" => Self::Json,
             "woff" => Self::Woff,
             "woff2" => Self::Woff2,
             "png" => Self::Png,
+            "jpg" | "jpeg" => Self::Jpeg,
+            "gif" => Self::Gif,
+            "ico" => Self::Ico,
             _ => return None,
         };
         Some(ty)
     }
+
+    /// Picks the type off of the file extension with extension-less files under `.well-known/`
+    /// (like ACME challenges) being plain text
+    pub fn from_rel_path(rel_path: &str) -> Option<Self> {
+        let file_name = rel_path.rsplit('/').next()?;
+        match file_name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => Self::from_file_ext(ext),
+            _ if rel_path.starts_with(".well-known/") => Some(Self::Txt),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charset_only_on_text() {
+        let table = [
+            (ContentType::Html, "text/html; charset=utf-8"),
+            (ContentType::Js, "application/javascript; charset=utf-8"),
+            (ContentType::Svg, "image/svg+xml; charset=utf-8"),
+            (ContentType::Css, "text/css; charset=utf-8"),
+            (ContentType::Xml, "application/xml; charset=utf-8"),
+            (ContentType::Rss, "application/rss+xml; charset=utf-8"),
+            (ContentType::Atom, "application/atom+xml; charset=utf-8"),
+            (ContentType::Txt, "text/plain; charset=utf-8"),
+            (ContentType::Json, "application/json"),
+            (ContentType::Woff, "font/woff"),
+            (ContentType::Woff2, "font/woff2"),
+            (ContentType::Png, "image/png"),
+            (ContentType::Jpeg, "image/jpeg"),
+            (ContentType::Gif, "image/gif"),
+            (ContentType::Ico, "image/x-icon"),
+        ];
+        for (ty, expected) in table {
+            let value = ty.to_header_value();
+            assert_eq!(value, expected, "{ty:?}");
+            let has_charset = value.to_str().unwrap().contains("charset=");
+            let expects_charset = ty.is_text() && ty != ContentType::Json;
+            assert_eq!(has_charset, expects_charset, "{ty:?}");
+        }
+    }
+
+    #[test]
+    fn type_from_rel_path() {
+        let table = [
+            ("index.html", Some(ContentType::Html)),
+            ("posts/feed.json", Some(ContentType::Json)),
+            (".well-known/security.txt", Some(ContentType::Txt)),
+            (".well-known/acme-challenge/token", Some(ContentType::Txt)),
+            (".well-known/assetlinks.json", Some(ContentType::Json)),
+            ("LICENSE", None),
+            (".hidden", None),
+            ("img/blob.bin", None),
+        ];
+        for (rel_path, expected) in table {
+            assert_eq!(ContentType::from_rel_path(rel_path), expected, "{rel_path}");
+        }
+    }
+
+    #[test]
+    fn fewer_encodings_take_less_space() {
+        let contents = "<p>hello, world</p>".repeat(100);
+        let compression = |encodings| CompressionConfig {
+            encodings,
+            brotli_lgwin: CompressionConfig::DEFAULT_BROTLI_LGWIN,
+            cache_dir: None,
+            poor_ratio: CompressionConfig::DEFAULT_POOR_RATIO,
+        };
+        let both = TextFile::new(
+            contents.clone(),
+            compression(&[Encoding::Gzip, Encoding::Brotli]),
+        );
+        let br_only = TextFile::new(contents, compression(&[Encoding::Brotli]));
+        assert_eq!(both.stored_encodings(), 2);
+        assert_eq!(br_only.stored_encodings(), 1);
+        assert!(!br_only.has(Encoding::Gzip));
+        assert!(br_only.total_size() < both.total_size());
+    }
+
+    #[test]
+    fn small_brotli_window_round_trips() {
+        use std::io::Read;
+
+        // big enough for the window size to matter
+        let contents: String = (0..40_000).map(|i| format!("<p>{i}</p>")).collect();
+        assert!(contents.len() > 1 << 18);
+        let compression = CompressionConfig {
+            encodings: &[Encoding::Brotli],
+            brotli_lgwin: 18,
+            cache_dir: None,
+            poor_ratio: CompressionConfig::DEFAULT_POOR_RATIO,
+        };
+        let text_file = TextFile::new(contents.clone(), compression);
+        let compressed = text_file.br_compressed.unwrap();
+
+        let mut decompressed = String::new();
+        brotli::Decompressor::new(&*compressed, 4_096)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, contents);
+    }
+
+    #[test]
+    fn compression_cache() {
+        let cache_dir =
+            std::env::temp_dir().join(format!("compression-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&cache_dir);
+        let compression = CompressionConfig {
+            encodings: &[Encoding::Gzip, Encoding::Brotli],
+            brotli_lgwin: CompressionConfig::DEFAULT_BROTLI_LGWIN,
+            cache_dir: Some(&cache_dir),
+            poor_ratio: CompressionConfig::DEFAULT_POOR_RATIO,
+        };
+        let contents = "<p>cache me if you can</p>".repeat(100);
+        let uncached = TextFile::new(
+            contents.clone(),
+            CompressionConfig {
+                cache_dir: None,
+                ..compression
+            },
+        );
+
+        // a miss populates the cache
+        let first = TextFile::new(contents.clone(), compression);
+        assert_eq!(first.gz_compressed, uncached.gz_compressed);
+        assert_eq!(first.br_compressed, uncached.br_compressed);
+        let mut entries: Vec<_> = fs::read_dir(&cache_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        entries.sort();
+        assert_eq!(entries.len(), 2);
+
+        // a hit is used as is
+        let br_entry = entries
+            .iter()
+            .find(|path| path.extension().unwrap() == "br")
+            .unwrap();
+        let mut tampered = fs::read(br_entry).unwrap();
+        tampered.truncate(8);
+        tampered.extend_from_slice(b"not actually brotli");
+        let checksum = XxHash64::oneshot(cache::CHECKSUM_SEED, &tampered[8..]);
+        tampered[..8].copy_from_slice(&checksum.to_le_bytes());
+        fs::write(br_entry, &tampered).unwrap();
+        let hit = TextFile::new(contents.clone(), compression);
+        assert_eq!(hit.br_compressed.unwrap(), &tampered[8..]);
+
+        // while a corrupt entry gets recomputed and repaired
+        fs::write(br_entry, b"garbage").unwrap();
+        let repaired = TextFile::new(contents.clone(), compression);
+        assert_eq!(repaired.br_compressed, uncached.br_compressed);
+        let hit = TextFile::new(contents, compression);
+        assert_eq!(hit.br_compressed, uncached.br_compressed);
+
+        // other settings get their own entries
+        let lower_window = CompressionConfig {
+            brotli_lgwin: 18,
+            ..compression
+        };
+        TextFile::new("<p>cache me if you can</p>".repeat(100), lower_window);
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 3);
+
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
 }
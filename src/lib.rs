@@ -1,7 +1,23 @@
+mod audit;
+mod cache;
+mod config;
 mod extract;
 mod file;
 mod middleware;
 mod router;
+mod serve;
 mod util;
 
-pub use router::router;
+pub use audit::{PoorCompression, audit_compression};
+pub use config::{
+    AccessLog, CacheScope, CacheScopes, Config, ExtraType, Limits, LogFormat, PathCacheScope,
+    PathGlobs, Probes, Robots, RobotsPolicy, RootFallback, StartupSummary, Throttle, TrailingSlash,
+    UrlUnsafeNames, UserAgentPatterns,
+};
+pub use extract::Encoding;
+pub use file::{ContentType, CustomType};
+pub use middleware::RecorderHandle;
+pub use router::{LoadError, STARTUP_SUMMARY_TARGET, router, router_with_recorder};
+#[cfg(unix)]
+pub use serve::serve_unix;
+pub use serve::{Server, ShutdownHandle, serve};
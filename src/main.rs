@@ -1,8 +1,11 @@
-use std::{array, env, net::Ipv4Addr, process};
+use std::{env, fmt, net::Ipv4Addr, path::PathBuf, process, time::Duration};
 
-use a_blog_out_of_deep_space::router;
+use a_blog_out_of_deep_space::{
+    Config, PoorCompression, STARTUP_SUMMARY_TARGET, ShutdownHandle, router_with_recorder, serve,
+};
+use axum::Router;
 use tokio::net::TcpListener;
-use tracing_subscriber::{EnvFilter, filter::LevelFilter, fmt, prelude::*};
+use tracing_subscriber::{EnvFilter, filter::LevelFilter, prelude::*};
 
 // TODO: camino for utf8 paths?
 // TODO: strip exif data off of images?
@@ -10,40 +13,411 @@ use tracing_subscriber::{EnvFilter, filter::LevelFilter, fmt, prelude::*};
 async fn main() {
     // setup logging
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(tracing_subscriber::fmt::layer())
         .with(
             EnvFilter::builder()
                 .with_env_var("LOG")
                 .with_default_directive(LevelFilter::DEBUG.into())
                 .from_env()
-                .unwrap(),
+                .unwrap()
+                // operators should always see what got loaded, even with a stricter filter
+                .add_directive(
+                    format!("{STARTUP_SUMMARY_TARGET}=info")
+                        .parse()
+                        .expect("the directive is valid"),
+                ),
         )
         .init();
 
     // parse cli args
     let mut args = env::args();
-    let args = array::from_fn(|_| args.next());
-    let dir_to_serve = match args {
-        [Some(_), Some(dir), None] if !["-h", "--help", "help"].contains(&&*dir) => dir,
-        [None, ..] => panic!("Somehow arg0 is unset...?"),
-        [Some(bin), ..] => {
+    let bin = args.next().expect("Somehow arg0 is unset...?");
+    let ServeArgs {
+        dir: dir_to_serve,
+        audit_compression,
+        no_compression,
+        no_cache,
+        mount_prefix,
+        unix_socket,
+    } = match Cli::parse(args, env::var(DIR_ENV_VAR).ok()) {
+        Ok(Cli::Serve(serve_args)) => serve_args,
+        Ok(Cli::Help) => {
+            println!("{}", help(&bin));
+            return;
+        }
+        Ok(Cli::Version) => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(err) => {
             eprintln!(
-                "Usage: {bin} <DIR_TO_SERVE>\n\
-                \n\
-                Arguments:\n  \
-                <DIR_TO_SERVE>  Directory containing a config file and files to serve"
+                "error: {err}\n\nUsage: {}\n\nFor more information, try '--help'",
+                usage(&bin)
             );
             process::exit(1);
         }
     };
+    if cfg!(not(unix)) && unix_socket.is_some() {
+        eprintln!("error: '--unix' is unsupported on this platform");
+        process::exit(1);
+    }
     tracing::info!("Loading {dir_to_serve}...");
+    let dir_to_serve = PathBuf::from(dir_to_serve);
+    let mut config = match Config::load(&dir_to_serve) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Failed loading config: {err}");
+            process::exit(1);
+        }
+    };
+
+    if no_compression {
+        config.encodings.clear();
+    }
+    config.no_cache |= no_cache;
+    if let Some(mount_prefix) = mount_prefix {
+        config.mount_prefix = mount_prefix;
+    }
+
+    if audit_compression {
+        let poorly_compressed = a_blog_out_of_deep_space::audit_compression(&dir_to_serve, &config);
+        for PoorCompression { path, ratio } in &poorly_compressed {
+            match ratio {
+                Some(ratio) => println!("{ratio:.2} {path}"),
+                None => println!("n/a  {path} (not valid UTF-8)"),
+            }
+        }
+        let code = if poorly_compressed.is_empty() { 0 } else { 1 };
+        process::exit(code);
+    }
 
     // launch server
-    let app = router(dir_to_serve.into());
+    let drain_timeout = Duration::from_secs(config.drain_timeout_secs);
+    let (app, recorder) = match router_with_recorder(dir_to_serve, config) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("Failed loading directory: {err}");
+            process::exit(1);
+        }
+    };
+    match unix_socket {
+        #[cfg(unix)]
+        Some(socket_path) => serve_unix(app, socket_path, drain_timeout).await,
+        #[cfg(not(unix))]
+        Some(_) => unreachable!("checked above"),
+        None => serve_tcp(app, drain_timeout).await,
+    }
+    // the server is done, so get the trailing entries into the access log before exiting
+    recorder.drain().await;
+}
+
+async fn serve_tcp(app: Router, drain_timeout: Duration) {
     let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 8080))
         .await
         .unwrap();
     // TODO: display server addr
     tracing::info!("Launching server...");
-    axum::serve(listener, app).await.unwrap();
+    let (server, shutdown) = serve(listener, app, drain_timeout).unwrap();
+    shutdown_on_signal(shutdown);
+    server.wait().await.unwrap();
+}
+
+#[cfg(unix)]
+async fn serve_unix(app: Router, socket_path: PathBuf, drain_timeout: Duration) {
+    use std::{fs, os::unix::fs::FileTypeExt};
+
+    use tokio::net::UnixListener;
+
+    // a socket left behind by an unclean exit would make binding fail. only ever remove actual
+    // sockets though, so that a typo can't delete some unrelated file
+    if let Ok(meta) = fs::symlink_metadata(&socket_path)
+        && meta.file_type().is_socket()
+    {
+        tracing::info!(socket_path = %socket_path.display(), "Removing stale socket");
+        if let Err(err) = fs::remove_file(&socket_path) {
+            eprintln!("Failed removing stale socket: {err}");
+            process::exit(1);
+        }
+    }
+    let listener = match UnixListener::bind(&socket_path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Failed binding {}: {err}", socket_path.display());
+            process::exit(1);
+        }
+    };
+    tracing::info!(socket_path = %socket_path.display(), "Launching server...");
+    // NOTE: there's no `ConnectInfo<SocketAddr>` for unix sockets, so the client is unknown
+    let (server, shutdown) =
+        a_blog_out_of_deep_space::serve_unix(listener, app, drain_timeout).unwrap();
+    shutdown_on_signal(shutdown);
+    server.wait().await.unwrap();
+    if let Err(err) = fs::remove_file(&socket_path) {
+        tracing::warn!(%err, "Failed cleaning up socket");
+    }
+}
+
+fn shutdown_on_signal(shutdown: ShutdownHandle) {
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        shutdown.shutdown();
+    });
+}
+
+/// Resolves on ctrl-c, or `SIGTERM` on unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed installing ctrl-c handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        signal(SignalKind::terminate())
+            .expect("failed installing SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+    tracing::info!("Shutting down...");
+}
+
+/// Where the directory to serve comes from when it's not passed as an argument
+const DIR_ENV_VAR: &str = "BLOG_DIR";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Cli {
+    Help,
+    Version,
+    Serve(ServeArgs),
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ServeArgs {
+    dir: String,
+    audit_compression: bool,
+    no_compression: bool,
+    no_cache: bool,
+    mount_prefix: Option<String>,
+    unix_socket: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum CliError {
+    UnknownFlag(String),
+    MissingValue(&'static str),
+    MissingDir,
+    UnexpectedArg(String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownFlag(flag) => write!(f, "unexpected flag '{flag}'"),
+            Self::MissingValue(flag) => write!(f, "missing a value for '{flag}'"),
+            Self::MissingDir => write!(
+                f,
+                "missing the directory to serve (pass one or set ${DIR_ENV_VAR})"
+            ),
+            Self::UnexpectedArg(arg) => write!(f, "unexpected argument '{arg}'"),
+        }
+    }
+}
+
+impl Cli {
+    /// Parses the args that come after arg0 with `env_dir` from [`DIR_ENV_VAR`]
+    ///
+    /// `--help` and `--version` win over everything else, and anything after a `--` is taken as
+    /// is. An empty `env_dir` counts as unset
+    fn parse(
+        args: impl IntoIterator<Item = String>,
+        env_dir: Option<String>,
+    ) -> Result<Self, CliError> {
+        let mut dir = None;
+        let mut audit_compression = false;
+        let mut no_compression = false;
+        let mut no_cache = false;
+        let mut mount_prefix = None;
+        let mut unix_socket = None;
+        let mut flags_done = false;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                _ if flags_done => {}
+                "-h" | "--help" | "help" => return Ok(Self::Help),
+                "-V" | "--version" => return Ok(Self::Version),
+                "--audit-compression" => {
+                    audit_compression = true;
+                    continue;
+                }
+                "--no-compression" => {
+                    no_compression = true;
+                    continue;
+                }
+                "--no-cache" => {
+                    no_cache = true;
+                    continue;
+                }
+                "--mount-prefix" => {
+                    let prefix = args
+                        .next()
+                        .ok_or(CliError::MissingValue("--mount-prefix"))?;
+                    mount_prefix = Some(prefix);
+                    continue;
+                }
+                "--unix" => {
+                    let path = args.next().ok_or(CliError::MissingValue("--unix"))?;
+                    unix_socket = Some(path.into());
+                    continue;
+                }
+                "--" => {
+                    flags_done = true;
+                    continue;
+                }
+                flag if flag.starts_with('-') => return Err(CliError::UnknownFlag(arg)),
+                _ => {}
+            }
+
+            match dir {
+                None => dir = Some(arg),
+                Some(_) => return Err(CliError::UnexpectedArg(arg)),
+            }
+        }
+
+        let dir = dir
+            .or(env_dir.filter(|dir| !dir.is_empty()))
+            .ok_or(CliError::MissingDir)?;
+        Ok(Self::Serve(ServeArgs {
+            dir,
+            audit_compression,
+            no_compression,
+            no_cache,
+            mount_prefix,
+            unix_socket,
+        }))
+    }
+}
+
+fn usage(bin: &str) -> String {
+    format!("{bin} [OPTIONS] <DIR_TO_SERVE>")
+}
+
+fn help(bin: &str) -> String {
+    format!(
+        "{} {}\n\
+        \n\
+        Usage: {}\n\
+        \n\
+        Arguments:\n  \
+        <DIR_TO_SERVE>  Directory containing an optional config file and files to serve, or a\n                  \
+        single file to serve at `/`. Defaults to ${}\n\
+        \n\
+        Options:\n      \
+        --audit-compression      List files that compress poorly and exit non-zero if any do\n      \
+        --no-compression         Always serve files uncompressed (same as `encodings = []`)\n      \
+        --no-cache               Never let responses get cached (same as `no_cache = true`)\n      \
+        --mount-prefix <PREFIX>  Serve everything under a path prefix like `/blog`\n      \
+        --unix <PATH>            Listen on a unix domain socket instead of TCP port 8080\n  \
+        -h, --help                   Print help\n  \
+        -V, --version                Print version",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        usage(bin),
+        DIR_ENV_VAR,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, CliError> {
+        parse_w_env(args, None)
+    }
+
+    fn parse_w_env(args: &[&str], env_dir: Option<&str>) -> Result<Cli, CliError> {
+        Cli::parse(
+            args.iter().map(|arg| arg.to_string()),
+            env_dir.map(Into::into),
+        )
+    }
+
+    #[test]
+    fn cli() {
+        let serve = |dir: &str| ServeArgs {
+            dir: dir.into(),
+            ..ServeArgs::default()
+        };
+        assert_eq!(parse(&["site"]), Ok(Cli::Serve(serve("site"))));
+        let audit = Cli::Serve(ServeArgs {
+            audit_compression: true,
+            ..serve("site")
+        });
+        assert_eq!(parse(&["--audit-compression", "site"]), Ok(audit));
+        let audit = Cli::Serve(ServeArgs {
+            audit_compression: true,
+            ..serve("site")
+        });
+        assert_eq!(parse(&["site", "--audit-compression"]), Ok(audit));
+        let unix = Cli::Serve(ServeArgs {
+            unix_socket: Some("/run/blog.sock".into()),
+            ..serve("site")
+        });
+        assert_eq!(parse(&["--unix", "/run/blog.sock", "site"]), Ok(unix));
+        let no_compression = Cli::Serve(ServeArgs {
+            no_compression: true,
+            ..serve("site")
+        });
+        assert_eq!(parse(&["site", "--no-compression"]), Ok(no_compression));
+        let no_cache = Cli::Serve(ServeArgs {
+            no_cache: true,
+            ..serve("site")
+        });
+        assert_eq!(parse(&["--no-cache", "site"]), Ok(no_cache));
+        let mount_prefix = Cli::Serve(ServeArgs {
+            mount_prefix: Some("/blog".into()),
+            ..serve("site")
+        });
+        assert_eq!(
+            parse(&["site", "--mount-prefix", "/blog"]),
+            Ok(mount_prefix)
+        );
+        assert_eq!(parse(&["--", "-site"]), Ok(Cli::Serve(serve("-site"))));
+        assert_eq!(parse(&["site", "--help"]), Ok(Cli::Help));
+        assert_eq!(parse(&["--version"]), Ok(Cli::Version));
+
+        // the env var is only a fallback
+        assert_eq!(parse_w_env(&[], Some("env")), Ok(Cli::Serve(serve("env"))));
+        assert_eq!(
+            parse_w_env(&["site"], Some("env")),
+            Ok(Cli::Serve(serve("site")))
+        );
+        assert_eq!(parse_w_env(&[], Some("")), Err(CliError::MissingDir));
+
+        assert_eq!(parse(&[]), Err(CliError::MissingDir));
+        assert_eq!(
+            parse(&["site", "--unix"]),
+            Err(CliError::MissingValue("--unix"))
+        );
+        assert_eq!(
+            parse(&["site", "--mount-prefix"]),
+            Err(CliError::MissingValue("--mount-prefix"))
+        );
+        assert_eq!(
+            parse(&["--port", "site"]),
+            Err(CliError::UnknownFlag("--port".into()))
+        );
+        assert_eq!(
+            parse(&["site", "other"]),
+            Err(CliError::UnexpectedArg("other".into()))
+        );
+    }
 }
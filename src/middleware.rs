@@ -1,45 +1,69 @@
 use std::{
     convert::Infallible,
+    fmt,
+    fs::OpenOptions,
+    future, io,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
     time::{Duration, Instant, SystemTime},
 };
 
 use axum::{
-    extract::Request,
-    http::{HeaderMap, Method, StatusCode, Uri},
+    body::{Body, Bytes},
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri, Version, header},
+    middleware::Next,
     response::Response,
 };
-use flume::{Sender, r#async::RecvStream};
-use futures_util::stream::StreamExt;
+use flume::{Receiver, Sender};
+use futures_util::stream::{self, StreamExt};
 use pin_project_lite::pin_project;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::oneshot,
+    task::JoinHandle,
+    time::MissedTickBehavior,
+};
 use tower::{Layer, Service};
 
-use crate::util::disp;
+use crate::{
+    config::{AccessLog, LogFormat, Throttle, UserAgentPatterns},
+    util::{MONTHS, UtcDateTime, disp},
+};
 
 #[derive(Clone, Debug)]
-#[allow(dead_code)]
 struct ReqMetadata {
     uri: Uri,
     method: Method,
+    version: Version,
     headers: HeaderMap,
+    client_addr: Option<SocketAddr>,
 }
 
 impl From<&Request> for ReqMetadata {
     fn from(req: &Request) -> Self {
         let uri = req.uri().to_owned();
         let method = req.method().to_owned();
+        let version = req.version();
         let headers = req.headers().to_owned();
+        let client_addr = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
         Self {
             uri,
             method,
+            version,
             headers,
+            client_addr,
         }
     }
 }
 
 #[derive(Clone, Debug)]
-#[allow(dead_code)]
 struct RespMetadata {
     status: StatusCode,
     headers: HeaderMap,
@@ -60,23 +84,300 @@ type RecorderEntry = (SystemTime, Duration, ReqMetadata, RespMetadata);
 #[derive(Clone)]
 pub struct RecorderLayer(Sender<RecorderEntry>);
 
+/// Drains the recorder from [`crate::router_with_recorder()`]
+///
+/// Merely dropping the handle leaves the recorder running
+pub struct RecorderHandle {
+    stop: oneshot::Sender<()>,
+    worker: JoinHandle<()>,
+}
+
+impl RecorderHandle {
+    /// Stops recording new entries and resolves once everything already recorded got written out
+    ///
+    /// Meant for after the server has stopped, so that the last requests still make it into the
+    /// access log before the process exits
+    pub async fn drain(self) {
+        // the worker already having stopped on its own is fine
+        let _ = self.stop.send(());
+        if let Err(err) = self.worker.await {
+            tracing::warn!(%err, "Recorder failed while draining");
+        }
+    }
+}
+
 impl RecorderLayer {
-    pub fn spawn() -> Self {
+    pub fn spawn(access_log: Option<&AccessLog>) -> io::Result<(Self, RecorderHandle)> {
+        let access_log = access_log
+            .map(|AccessLog { format, path }| {
+                let writer: Box<dyn AsyncWrite + Send + Unpin> = match path {
+                    // opened here, so that a bad path gets reported immediately
+                    Some(path) => {
+                        let file = OpenOptions::new().create(true).append(true).open(path)?;
+                        Box::new(tokio::fs::File::from_std(file))
+                    }
+                    None => Box::new(tokio::io::stdout()),
+                };
+                io::Result::Ok((*format, writer))
+            })
+            .transpose()?;
+
         let (send, recv) = flume::bounded(32);
-        let recv_stream: RecvStream<'static, RecorderEntry> = recv.into_stream();
-        tokio::spawn(async move {
-            recorder_worker(recv_stream).await;
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let stop = async move {
+            if stop_rx.await.is_err() {
+                // the handle was dropped without draining
+                future::pending::<()>().await;
+            }
+        };
+        let worker = tokio::spawn(async move {
+            recorder_worker(recv, stop, access_log).await;
         });
-        Self(send)
+        let handle = RecorderHandle {
+            stop: stop_tx,
+            worker,
+        };
+        Ok((Self(send), handle))
+    }
+}
+
+type AccessLogSink = Option<(LogFormat, Box<dyn AsyncWrite + Send + Unpin>)>;
+
+/// Records entries until either every sender is gone or `stop` resolves
+async fn recorder_worker(
+    recv: Receiver<RecorderEntry>,
+    stop: impl Future<Output = ()>,
+    mut access_log: AccessLogSink,
+) {
+    const SUMMARY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+    let mut revalidation = RevalidationStats::default();
+    let mut last_summary = revalidation;
+    let mut summary_interval = tokio::time::interval(SUMMARY_INTERVAL);
+    summary_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // the first tick completes immediately
+    summary_interval.tick().await;
+    tokio::pin!(stop);
+
+    loop {
+        let entry = tokio::select! {
+            entry = recv.recv_async() => match entry {
+                Ok(entry) => entry,
+                Err(_) => break,
+            },
+            _ = summary_interval.tick() => {
+                // stay quiet when nothing changed
+                if revalidation != last_summary {
+                    revalidation.log_summary();
+                    last_summary = revalidation;
+                }
+                continue;
+            }
+            _ = &mut stop => {
+                // dropping the receiver makes any later entries get dropped on the sender's
+                // side, so this drains whatever was already queued up
+                let queued: Vec<_> = recv.drain().collect();
+                drop(recv);
+                for entry in &queued {
+                    record(entry, &mut revalidation, &mut access_log).await;
+                }
+                break;
+            }
+        };
+
+        record(&entry, &mut revalidation, &mut access_log).await;
+    }
+
+    if revalidation != last_summary {
+        revalidation.log_summary();
+    }
+    if let Some((_, writer)) = &mut access_log
+        && let Err(err) = writer.flush().await
+    {
+        tracing::warn!(%err, "Failed flushing access log");
+    }
+    tracing::debug!("Recorder drained");
+}
+
+async fn record(
+    entry: &RecorderEntry,
+    revalidation: &mut RevalidationStats,
+    access_log: &mut AccessLogSink,
+) {
+    let (time, duration, req, resp) = entry;
+    tracing::trace!(time = %disp::Time(*time), duration = %disp::Duration(*duration), ?req, ?resp);
+    revalidation.record(req, resp);
+
+    if let Some((format, writer)) = access_log {
+        let log_entry = AccessLogEntry::from(entry);
+        let mut line = match format {
+            LogFormat::Combined => log_entry.to_string(),
+            LogFormat::Json => serde_json::to_string(&log_entry).unwrap(),
+        };
+        line.push('\n');
+        let res = async {
+            writer.write_all(line.as_bytes()).await?;
+            writer.flush().await
+        };
+        if let Err(err) = res.await {
+            tracing::warn!(%err, "Failed writing access log entry");
+        }
+    }
+}
+
+/// Running counts of how effective revalidation is
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct RevalidationStats {
+    /// Requests that carried a validator (`If-None-Match` or `If-Modified-Since`)
+    conditional: u64,
+    /// Conditional requests answered with a bodiless `304 Not Modified`
+    not_modified: u64,
+    /// Any request answered with a full `200 OK` body
+    full: u64,
+}
+
+impl RevalidationStats {
+    fn record(&mut self, req: &ReqMetadata, resp: &RespMetadata) {
+        let is_conditional = req.headers.contains_key(header::IF_NONE_MATCH)
+            || req.headers.contains_key(header::IF_MODIFIED_SINCE);
+        if is_conditional {
+            self.conditional += 1;
+        }
+        match resp.status {
+            StatusCode::NOT_MODIFIED if is_conditional => self.not_modified += 1,
+            StatusCode::OK => self.full += 1,
+            _ => {}
+        }
+    }
+
+    /// The fraction of conditional requests that returned a `304 Not Modified`
+    fn hit_ratio(&self) -> Option<f64> {
+        (self.conditional > 0).then(|| self.not_modified as f64 / self.conditional as f64)
+    }
+
+    fn log_summary(&self) {
+        let Self {
+            conditional,
+            not_modified,
+            full,
+        } = *self;
+        let hit_ratio = self
+            .hit_ratio()
+            .map(|ratio| format!("{:.0}%", ratio * 100.0));
+        tracing::info!(
+            conditional,
+            not_modified,
+            full,
+            hit_ratio = hit_ratio.as_deref().unwrap_or("-"),
+            "Revalidation summary",
+        );
+    }
+}
+
+/// The fields shared by all of the access log formats
+#[derive(Serialize)]
+struct AccessLogEntry<'a> {
+    client_ip: Option<IpAddr>,
+    #[serde(serialize_with = "serialize_rfc3339")]
+    timestamp: SystemTime,
+    method: &'a str,
+    path: &'a str,
+    #[serde(serialize_with = "serialize_version")]
+    version: Version,
+    #[serde(serialize_with = "serialize_status")]
+    status: StatusCode,
+    bytes: Option<u64>,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    duration_ms: f64,
+}
+
+impl<'a> From<&'a RecorderEntry> for AccessLogEntry<'a> {
+    fn from((time, duration, req, resp): &'a RecorderEntry) -> Self {
+        let req_header = |name| req.headers.get(name).and_then(|value| value.to_str().ok());
+        Self {
+            client_ip: req.client_addr.map(|addr| addr.ip()),
+            timestamp: *time,
+            method: req.method.as_str(),
+            path: req
+                .uri
+                .path_and_query()
+                .map_or("/", |p_and_q| p_and_q.as_str()),
+            version: req.version,
+            status: resp.status,
+            bytes: resp
+                .headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|len| len.to_str().ok()?.parse().ok()),
+            referer: req_header(header::REFERER),
+            user_agent: req_header(header::USER_AGENT),
+            duration_ms: duration.as_secs_f64() * 1_000.0,
+        }
     }
 }
 
-async fn recorder_worker(mut recv_stream: RecvStream<'static, RecorderEntry>) {
-    while let Some((time, duration, req, resp)) = recv_stream.next().await {
-        tracing::trace!(time = %disp::Time(time), duration = %disp::Duration(duration), ?req, ?resp);
+/// Displays as the Combined Log Format with the duration appended
+impl fmt::Display for AccessLogEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.client_ip {
+            Some(ip) => write!(f, "{ip}")?,
+            None => f.write_str("-")?,
+        }
+        let UtcDateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            ..
+        } = self.timestamp.into();
+        let month = MONTHS[usize::from(month) - 1];
+        write!(
+            f,
+            " - - [{day:02}/{month}/{year}:{hour:02}:{minute:02}:{second:02} +0000] \
+            \"{} {} {:?}\" {}",
+            self.method,
+            self.path,
+            self.version,
+            self.status.as_u16(),
+        )?;
+        match self.bytes {
+            Some(bytes) => write!(f, " {bytes}")?,
+            None => f.write_str(" -")?,
+        }
+        for value in [self.referer, self.user_agent] {
+            // quotes are the only thing that would break out of the field
+            write!(f, " \"{}\"", value.unwrap_or("-").replace('"', "\\\""))?;
+        }
+        write!(f, " {:.3}", self.duration_ms)
     }
 }
 
+fn serialize_rfc3339<S: serde::Serializer>(time: &SystemTime, ser: S) -> Result<S::Ok, S::Error> {
+    let UtcDateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        millis,
+        ..
+    } = (*time).into();
+    ser.collect_str(&format_args!(
+        "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{millis:03}Z"
+    ))
+}
+
+fn serialize_version<S: serde::Serializer>(version: &Version, ser: S) -> Result<S::Ok, S::Error> {
+    ser.collect_str(&format_args!("{version:?}"))
+}
+
+fn serialize_status<S: serde::Serializer>(status: &StatusCode, ser: S) -> Result<S::Ok, S::Error> {
+    ser.serialize_u16(status.as_u16())
+}
+
 impl<S> Layer<S> for RecorderLayer {
     type Service = Recorder<S>;
 
@@ -155,3 +456,215 @@ where
         }
     }
 }
+
+/// Delays the response and then trickles out its body according to `throttle`
+///
+/// Sits inside of the recorder, so that the added latency shows up in the logged durations
+pub async fn throttle(
+    State(throttle): State<Option<Throttle>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(Throttle {
+        latency_ms,
+        bytes_per_sec,
+    }) = throttle
+    else {
+        return next.run(req).await;
+    };
+
+    let resp = next.run(req).await;
+    tokio::time::sleep(Duration::from_millis(latency_ms)).await;
+    let Some(bytes_per_sec) = bytes_per_sec else {
+        return resp;
+    };
+
+    // send roughly 10 slices a second, so that progress still looks smooth
+    let slice_len = (bytes_per_sec.get() / 10).max(1) as usize;
+    let (parts, body) = resp.into_parts();
+    let state = (body.into_data_stream(), Bytes::new());
+    let slices = stream::unfold(state, move |(mut chunks, mut pending)| async move {
+        while pending.is_empty() {
+            match chunks.next().await? {
+                Ok(chunk) => pending = chunk,
+                Err(err) => return Some((Err(err), (chunks, pending))),
+            }
+        }
+        let slice = pending.split_to(slice_len.min(pending.len()));
+        let delay = Duration::from_secs_f64(slice.len() as f64 / bytes_per_sec.get() as f64);
+        tokio::time::sleep(delay).await;
+        Some((Ok(slice), (chunks, pending)))
+    });
+    Response::from_parts(parts, Body::from_stream(slices))
+}
+
+/// Serves clients whose `User-Agent` matches `patterns` uncompressed by overriding their
+/// `Accept-Encoding`
+///
+/// Whether a response is compressed then also depends on the `User-Agent`, so any response that
+/// varies by `Accept-Encoding` gets told to vary by it too
+pub async fn identity_for_user_agents(
+    State(patterns): State<Arc<UserAgentPatterns>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if patterns.is_empty() {
+        return next.run(req).await;
+    }
+
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok());
+    if let Some(user_agent) = user_agent.filter(|user_agent| patterns.is_match(user_agent)) {
+        tracing::debug!(user_agent, "Forcing identity encoding for user agent");
+        req.headers_mut().insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("identity"),
+        );
+    }
+
+    let mut resp = next.run(req).await;
+    let vary = resp
+        .headers()
+        .get(header::VARY)
+        .and_then(|vary| vary.to_str().ok())
+        .filter(|vary| {
+            vary.split(',').any(|name| {
+                name.trim()
+                    .eq_ignore_ascii_case(header::ACCEPT_ENCODING.as_str())
+            })
+        });
+    if let Some(vary) = vary {
+        let vary = format!("{vary}, {}", header::USER_AGENT);
+        resp.headers_mut().insert(
+            header::VARY,
+            vary.try_into().expect("still a valid header value"),
+        );
+    }
+    resp
+}
+
+/// Describes how the response was picked in an `X-ABOODS-Debug` header when `enabled`
+///
+/// e.g. `enc=br; why=negotiated; revalidated=false; cc="public, max-age=300"; route=/index.html`
+pub async fn debug_header(State(enabled): State<bool>, req: Request, next: Next) -> Response {
+    static DEBUG: HeaderName = HeaderName::from_static("x-aboods-debug");
+
+    if !enabled {
+        return next.run(req).await;
+    }
+
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("-", MatchedPath::as_str)
+        .to_owned();
+    let mut resp = next.run(req).await;
+
+    let headers = resp.headers();
+    let content_encoding = headers.get(header::CONTENT_ENCODING);
+    let revalidated = resp.status() == StatusCode::NOT_MODIFIED;
+    // only files that are stored compressed vary on the encoding
+    let (enc, why) = match (content_encoding, headers.contains_key(header::VARY)) {
+        _ if revalidated => ("-", "no-body"),
+        (Some(encoding), _) => (encoding.to_str().unwrap_or("?"), "negotiated"),
+        (None, true) => ("identity", "not-accepted"),
+        (None, false) => ("identity", "uncompressed"),
+    };
+    let cc = headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|cc| cc.to_str().ok())
+        .unwrap_or("-");
+    let debug =
+        format!("enc={enc}; why={why}; revalidated={revalidated}; cc=\"{cc}\"; route={route}");
+    if let Ok(debug) = HeaderValue::try_from(debug) {
+        resp.headers_mut().insert(DEBUG.clone(), debug);
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::HeaderValue;
+
+    fn entry() -> RecorderEntry {
+        let mut req_headers = HeaderMap::new();
+        req_headers.insert(
+            header::USER_AGENT,
+            HeaderValue::from_static("curl/8.0 \"quoted\""),
+        );
+        let mut resp_headers = HeaderMap::new();
+        resp_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(654));
+        let req = ReqMetadata {
+            uri: "/posts/?page=2".parse().unwrap(),
+            method: Method::GET,
+            version: Version::HTTP_11,
+            headers: req_headers,
+            client_addr: Some(([127, 0, 0, 1], 51_234).into()),
+        };
+        let resp = RespMetadata {
+            status: StatusCode::OK,
+            headers: resp_headers,
+        };
+        // sun, 06 nov 1994 08:49:37.250 gmt
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(784_111_777_250);
+        (time, Duration::from_micros(1_500), req, resp)
+    }
+
+    #[test]
+    fn revalidation_stats() {
+        let (_, _, mut req, mut resp) = entry();
+        let mut stats = RevalidationStats::default();
+        assert_eq!(stats.hit_ratio(), None);
+
+        // a plain request only counts towards full responses
+        stats.record(&req, &resp);
+        req.headers
+            .insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"abc\""));
+        stats.record(&req, &resp);
+        resp.status = StatusCode::NOT_MODIFIED;
+        for _ in 0..2 {
+            stats.record(&req, &resp);
+        }
+        // revalidating by date counts just the same
+        req.headers.remove(header::IF_NONE_MATCH);
+        req.headers.insert(
+            header::IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"),
+        );
+        stats.record(&req, &resp);
+        assert_eq!(
+            stats,
+            RevalidationStats {
+                conditional: 4,
+                not_modified: 3,
+                full: 2,
+            }
+        );
+        assert_eq!(stats.hit_ratio(), Some(0.75));
+    }
+
+    #[test]
+    fn combined_format() {
+        let entry = entry();
+        let line = AccessLogEntry::from(&entry).to_string();
+        assert_eq!(
+            line,
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /posts/?page=2 HTTP/1.1\" 200 654 \
+            \"-\" \"curl/8.0 \\\"quoted\\\"\" 1.500"
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let entry = entry();
+        let line = serde_json::to_string(&AccessLogEntry::from(&entry)).unwrap();
+        assert_eq!(
+            line,
+            r#"{"client_ip":"127.0.0.1","timestamp":"1994-11-06T08:49:37.250Z","method":"GET","path":"/posts/?page=2","version":"HTTP/1.1","status":200,"bytes":654,"referer":null,"user_agent":"curl/8.0 \"quoted\"","duration_ms":1.5}"#
+        );
+    }
+}
@@ -1,14 +1,24 @@
 use std::{
     collections::BTreeMap,
-    path::PathBuf,
-    sync::Arc,
-    time::{Duration, Instant},
+    error::Error,
+    fmt, fs, io,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
-    extract::{Encoding, IfNoneMatch},
-    file::ServedFile,
-    middleware::RecorderLayer,
+    config::{
+        CacheScope, Config, Probes, RootFallback, StartupSummary, TrailingSlash, UrlUnsafeNames,
+    },
+    extract::{AcceptEncoding, IfModifiedSince, IfNoneMatch, Range},
+    file::{ContentType, ServedFile},
+    middleware::{RecorderHandle, RecorderLayer, debug_header, identity_for_user_agents, throttle},
     util::{TotalSize, disp},
 };
 
@@ -16,107 +26,873 @@ use axum::{
     BoxError, Router,
     body::Body,
     error_handling::HandleErrorLayer,
-    http::{StatusCode, header},
+    extract::{Request, State},
+    http::{StatusCode, Uri, header},
+    middleware::{self, Next},
     response::Response,
-    routing::get,
+    routing::{MethodRouter, get},
 };
 use tower::ServiceBuilder;
 use walkdir::WalkDir;
 
-// TODO: return an error in here instead of filtering out any bad entries?
-pub fn router(dir: PathBuf) -> Router {
+/// The `tracing` target of the summary that gets logged once loading finishes
+///
+/// See [`StartupSummary`]
+pub const STARTUP_SUMMARY_TARGET: &str = "startup_summary";
+
+#[derive(Debug)]
+pub enum LoadError {
+    FileTooLarge {
+        path: PathBuf,
+        size: u64,
+        limit: u64,
+    },
+    TotalTooLarge {
+        path: PathBuf,
+        limit: u64,
+    },
+    AccessLog(io::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileTooLarge { path, size, limit } => write!(
+                f,
+                "{} is {} which is over the per-file limit of {}",
+                path.display(),
+                disp::HumanBytes(*size as usize),
+                disp::HumanBytes(*limit as usize),
+            ),
+            Self::TotalTooLarge { path, limit } => write!(
+                f,
+                "loading {} would go over the total size limit of {}",
+                path.display(),
+                disp::HumanBytes(*limit as usize),
+            ),
+            Self::AccessLog(err) => write!(f, "failed opening the access log: {err}"),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+const ROBOTS_TXT: &str = "robots.txt";
+
+/// The error pages by their status code
+type StatusPages = Arc<BTreeMap<StatusCode, ServedFile>>;
+
+/// Loads everything in `dir` into a router serving it
+///
+/// `dir` can also be a single file which then gets served at both `/` and `/<FILE_NAME>`
+// TODO: return an error in here instead of filtering out any other bad entries?
+pub fn router(dir: PathBuf, config: Config) -> Result<Router, LoadError> {
+    router_with_recorder(dir, config).map(|(router, _)| router)
+}
+
+/// Like [`router()`], but also returns a handle for draining the access log on shutdown
+pub fn router_with_recorder(
+    dir: PathBuf,
+    config: Config,
+) -> Result<(Router, RecorderHandle), LoadError> {
+    let single_file = dir
+        .is_file()
+        .then(|| dir.file_name().and_then(|name| name.to_str()))
+        .flatten();
+    let root_document = config
+        .root_document
+        .as_deref()
+        .or(single_file)
+        .map(|doc| doc.trim_start_matches('/'));
+    let mut found_root_document = false;
+    let mut found_root_index = false;
+    let fallback_document = match &config.root_fallback {
+        Some(RootFallback::Serve(doc)) => Some(doc.trim_start_matches('/')),
+        _ => None,
+    };
+    let mut fallback_file = None;
+    // keyed by the normalized root, so that `/app` and `app/` can't register conflicting routes
+    let spa_roots: BTreeMap<_, _> = config
+        .spa_roots
+        .iter()
+        .map(|root| {
+            let root = root.trim_matches('/').to_owned();
+            let index = match root.as_str() {
+                "" => "index.html".to_owned(),
+                root => format!("{root}/index.html"),
+            };
+            (root, index)
+        })
+        .collect();
+    let mut spa_indices = BTreeMap::new();
+    let mut found_robots_txt = false;
+    let status_page_prefix = match config.status_page_dir.trim_matches('/') {
+        "" => String::new(),
+        status_page_dir => format!("{status_page_dir}/"),
+    };
     let mut not_found_page: Option<Arc<_>> = None;
     let mut status_pages = BTreeMap::new();
     let mut router = Router::new();
     let mut total_size = 0;
+    let mut loaded_files = Vec::new();
     let total_start = Instant::now();
 
-    for path in WalkDir::new(&dir).into_iter().filter_map(|res| {
-        let entry = res.ok()?;
-        let path = entry.into_path();
-        path.is_file().then_some(path)
-    }) {
-        let start = Instant::now();
+    // figure out what we're going to load first, so that the expensive part can be spread out
+    let mut to_load = Vec::new();
+    for (path, rel_path) in walk(&dir) {
+        // check the size on disk first to avoid reading in huge files entirely
+        if let Some(limit) = config.limits.max_file_size {
+            let size = fs::metadata(&path).map_or(0, |meta| meta.len());
+            if size > limit {
+                let err = LoadError::FileTooLarge { path, size, limit };
+                if config.limits.strict {
+                    return Err(err);
+                }
+                tracing::warn!(%err, "Skipping file");
+                continue;
+            }
+        }
 
-        let Some(served_file) = ServedFile::load(&path) else {
-            // TODO: log
-            continue;
+        // status code pages come exclusively from the status page dir which isn't served directly
+        let status_code = match rel_path.strip_prefix(&status_page_prefix) {
+            Some(name) => {
+                let status_code = name
+                    .strip_suffix(".html")
+                    .and_then(|name| name.parse::<StatusCode>().ok())
+                    .filter(|status_code| config.status_pages.contains(status_code));
+                if status_page_prefix.is_empty() {
+                    // a page picked up from the root of the site shadows what would otherwise be
+                    // ordinary content at the same path
+                    if let Some(status_code) = status_code {
+                        tracing::warn!(
+                            %rel_path,
+                            status = status_code.as_u16(),
+                            "Treating file as a status code page, so it isn't served as normal \
+                            content",
+                        );
+                    }
+                } else if status_code.is_none() {
+                    tracing::warn!(%rel_path, "Ignoring non-status code page in status page dir");
+                    continue;
+                }
+                status_code
+            }
+            None => None,
         };
-        let in_memory_size = served_file.total_size();
-        total_size += in_memory_size;
 
-        let rel_path = path
-            .strip_prefix(&dir)
-            .unwrap()
-            .components()
-            .map(|comp| comp.as_os_str().to_str().unwrap())
-            .collect::<Vec<_>>()
-            .join("/");
-
-        if let Some(status_code) = rel_path
-            .strip_suffix(".html")
-            .and_then(|name| name.parse::<StatusCode>().ok())
-        {
-            if status_code == StatusCode::NOT_FOUND {
-                not_found_page = Some(served_file.clone().into());
+        if let Some(unsafe_char) = rel_path.chars().find(|&c| !is_url_safe(c)) {
+            let action = match config.url_unsafe_names {
+                UrlUnsafeNames::Encode => "percent-encoding it",
+                UrlUnsafeNames::Skip => "skipping it",
+            };
+            tracing::warn!(%rel_path, ?unsafe_char, "Path isn't URL-safe, {action}");
+            if config.url_unsafe_names == UrlUnsafeNames::Skip {
+                continue;
             }
-            status_pages.insert(status_code, served_file);
-        } else {
-            // path must start with a `/`
-            let rel_path = format!("/{rel_path}");
-            let served_file = Arc::new(served_file);
-            let get_file = get(async |encoding, if_none_match| {
-                serve_file(encoding, if_none_match, served_file).await
+        }
+
+        to_load.push((path, rel_path, status_code));
+    }
+
+    // compression is by far the slowest part of loading, so a pool of workers pulls files to load
+    // off of a shared queue. results get added in their original order as soon as they're ready,
+    // so that routes get registered deterministically and the limits are still checked eagerly
+    let parallelism = config
+        .load_threads
+        .or_else(|| thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get);
+    let next_to_load = AtomicUsize::new(0);
+    router = thread::scope(|scope| {
+        let (send, recv) = flume::unbounded();
+        for _ in 0..parallelism.min(to_load.len()) {
+            let send = send.clone();
+            let (to_load, next_to_load, config) = (&to_load, &next_to_load, &config);
+            scope.spawn(move || {
+                loop {
+                    let i = next_to_load.fetch_add(1, Ordering::Relaxed);
+                    let Some((path, rel_path, _)) = to_load.get(i) else {
+                        break;
+                    };
+                    // the receiver only hangs up once loading has halted
+                    if send.send((i, load_file(path, rel_path, config))).is_err() {
+                        break;
+                    }
+                }
             });
-            // add equivalent routes on `/index.html` pages
-            if let Some(norm_path) = rel_path.strip_suffix("/index.html") {
-                // allow for no trailing slash as long as it leaves _something_ for the route path
-                if !norm_path.is_empty() {
-                    router = router.route(norm_path, get_file.clone());
+        }
+        drop(send);
+
+        // results can finish out of order, so hold onto them until it's their turn
+        let mut ready = BTreeMap::new();
+        let mut to_add = 0;
+        for (i, loaded) in &recv {
+            ready.insert(i, loaded);
+            while let Some(loaded) = ready.remove(&to_add) {
+                let (path, rel_path, status_code) = &to_load[to_add];
+                to_add += 1;
+                let Some((served_file, elapsed)) = loaded else {
+                    continue;
+                };
+                let in_memory_size = served_file.total_size();
+                if let Some(limit) = config.limits.max_total_size
+                    && (total_size + in_memory_size) as u64 > limit
+                {
+                    let err = LoadError::TotalTooLarge {
+                        path: path.clone(),
+                        limit,
+                    };
+                    if config.limits.strict {
+                        return Err(err);
+                    }
+                    tracing::warn!(%err, "Halting loading");
+                    return Ok(router);
                 }
-                router = router.route(&format!("{norm_path}/"), get_file.clone());
+                total_size += in_memory_size;
+                loaded_files.push((
+                    rel_path.as_str(),
+                    served_file.content_type().clone(),
+                    in_memory_size,
+                ));
+
+                if let Some(status_code) = *status_code {
+                    if status_code == StatusCode::NOT_FOUND {
+                        not_found_page = Some(served_file.clone().into());
+                    }
+                    status_pages.insert(status_code, served_file);
+                } else {
+                    let is_root_document = root_document == Some(rel_path.as_str());
+                    found_root_document |= is_root_document;
+                    found_robots_txt |= rel_path == ROBOTS_TXT;
+                    found_root_index |= rel_path == "index.html";
+                    if fallback_document == Some(rel_path.as_str()) {
+                        fallback_file = Some(served_file.clone());
+                    }
+                    for (root, index) in &spa_roots {
+                        if index == rel_path {
+                            spa_indices.insert(root.as_str(), Arc::new(served_file.clone()));
+                        }
+                    }
+                    router = add_file_routes(
+                        router,
+                        rel_path,
+                        served_file,
+                        &config,
+                        root_document.is_some(),
+                        is_root_document,
+                    );
+                }
+
+                tracing::debug!(
+                    %rel_path,
+                    elapsed = %disp::Duration(elapsed),
+                    in_memory_size = %disp::HumanBytes(in_memory_size),
+                    "Loaded file",
+                );
             }
-            router = router.route(&rel_path, get_file);
         }
+        Ok(router)
+    })?;
 
-        tracing::debug!(
-            %rel_path,
-            elapsed = %disp::Duration(start.elapsed()),
-            in_memory_size = %disp::HumanBytes(in_memory_size),
-            "Loaded file",
-        );
+    if let Some(root_document) = root_document.filter(|_| !found_root_document) {
+        tracing::warn!(root_document, "Missing root document");
+    }
+
+    let has_root_route = if root_document.is_some() {
+        found_root_document
+    } else {
+        found_root_index
+    };
+    match config.root_fallback.as_ref().filter(|_| !has_root_route) {
+        Some(RootFallback::Redirect(path)) => {
+            // configs built in code skip the check for a leading `/`
+            let location = format!(
+                "{}/{}",
+                config.normalized_mount_prefix(),
+                path.trim_start_matches('/'),
+            );
+            router = router.route("/", redirect_to(StatusCode::TEMPORARY_REDIRECT, location));
+        }
+        Some(RootFallback::Serve(_)) => match fallback_file {
+            Some(served_file) => {
+                router = router.route("/", file_route(Arc::new(served_file), &config));
+            }
+            None => tracing::warn!(fallback_document, "Missing root fallback document"),
+        },
+        None => {}
+    }
+
+    // the wildcard loses out to every actual file, so this only catches unknown paths
+    for (root, index) in &spa_roots {
+        match spa_indices.get(root.as_str()) {
+            Some(served_file) => {
+                let route = match root.as_str() {
+                    "" => "/{*rest}".to_owned(),
+                    root => format!("/{}/{{*rest}}", percent_encode_path(root)),
+                };
+                router = router.route(
+                    &route,
+                    spa_route(Arc::clone(served_file), not_found_page.clone(), &config),
+                );
+            }
+            None => tracing::warn!(%index, "Missing single-page app index"),
+        }
+    }
+
+    // a real `robots.txt` always wins over a generated one
+    if let Some(robots) = config.robots.as_ref().filter(|_| !found_robots_txt) {
+        let contents = robots.to_robots_txt().into_bytes();
+        let cache_scope = config.cache_scope_for(ROBOTS_TXT, &ContentType::Txt);
+        let served_file = ServedFile::from_contents(
+            contents,
+            ContentType::Txt,
+            config.compression_for(ROBOTS_TXT),
+        )
+        .with_cache_scope(cache_scope);
+        total_size += served_file.total_size();
+        router = add_file_routes(router, ROBOTS_TXT, served_file, &config, false, false);
+        tracing::debug!("Generated robots.txt");
     }
 
     tracing::info!(
+        target: STARTUP_SUMMARY_TARGET,
+        files = loaded_files.len(),
         elapsed = %disp::Duration(total_start.elapsed()),
         in_memory_size = %disp::HumanBytes(total_size),
         "Loaded directory",
     );
+    log_startup_summary(&config.startup_summary, loaded_files);
 
+    let status_pages = Arc::new(status_pages);
     // `axum` (at the time of writing) doesn't support passing state into the function for
     // `HandleError`, so instead we capture it in a closure here
-    let middleware_error_w_state =
-        async |encoding, err| handle_middleware_error(status_pages.into(), encoding, err).await;
+    let middleware_error_w_state = {
+        let status_pages = Arc::clone(&status_pages);
+        let retry_after = config.retry_after_secs;
+        async move |accept_encoding, if_none_match, err| {
+            handle_middleware_error(
+                status_pages,
+                retry_after,
+                accept_encoding,
+                if_none_match,
+                err,
+            )
+            .await
+        }
+    };
+
+    let files = router
+        .fallback(async move |accept_encoding, if_none_match| {
+            status_code_page(
+                not_found_page.as_deref(),
+                StatusCode::NOT_FOUND,
+                accept_encoding,
+                if_none_match,
+            )
+        })
+        // after routing, so that it can see the matched route
+        .layer(middleware::from_fn_with_state(
+            config.debug_header,
+            debug_header,
+        ));
+
+    if let Some(throttle) = config.throttle {
+        tracing::warn!(
+            ?throttle,
+            "THROTTLING ALL RESPONSES! This is only meant for local testing",
+        );
+    }
+
+    // middleware on a `Router` runs _after_ routing, so anything that needs to see (or rewrite)
+    // the request before it gets routed wraps an empty router that forwards everything to `files`
+    let (recorder, recorder_handle) =
+        RecorderLayer::spawn(config.access_log.as_ref()).map_err(LoadError::AccessLog)?;
+    let mount_prefix = Arc::from(config.normalized_mount_prefix());
+    let router = Router::new().fallback_service(files).layer(
+        // NOTE: when you add a fallible middleware here make sure that you handle the error in
+        // `handle_middleware_error`
+        ServiceBuilder::new()
+            // outermost, so that even error responses get one
+            .layer(middleware::from_fn(set_date))
+            .layer(HandleErrorLayer::new(middleware_error_w_state))
+            // TODO: allow customizing this value
+            .timeout(Duration::from_secs(60))
+            .load_shed()
+            // checked before the recorder, so that probes don't end up in the logs
+            .layer(middleware::from_fn_with_state(
+                Arc::new(config.probes),
+                reject_probes,
+            ))
+            // likewise for the malformed paths that scanners like to send
+            .layer(middleware::from_fn_with_state(
+                Arc::clone(&status_pages),
+                reject_malformed_paths,
+            ))
+            // and the odd headers from conformance scanners
+            .layer(middleware::from_fn_with_state(
+                Arc::clone(&status_pages),
+                reject_unsupported_headers,
+            ))
+            .layer(recorder)
+            .layer(middleware::from_fn_with_state(config.throttle, throttle))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(config.identity_user_agents),
+                identity_for_user_agents,
+            ))
+            .layer(middleware::from_fn_with_state(
+                Arc::clone(&status_pages),
+                normalize_path,
+            ))
+            // after normalizing, so that `/blog//posts` still gets matched
+            .layer(middleware::from_fn_with_state(
+                (mount_prefix, status_pages),
+                strip_mount_prefix,
+            )),
+    );
+    Ok((router, recorder_handle))
+}
+
+fn add_file_routes(
+    mut router: Router,
+    rel_path: &str,
+    served_file: ServedFile,
+    config: &Config,
+    has_root_document: bool,
+    is_root_document: bool,
+) -> Router {
+    // path must start with a `/` and match what clients would actually send
+    let rel_path = format!("/{}", percent_encode_path(rel_path));
+    let get_file = file_route(Arc::new(served_file), config);
+    // redirects go to what the client sees which includes the mount prefix
+    let mount_prefix = config.normalized_mount_prefix();
+    let redirect = |path: &str| {
+        redirect_to(
+            StatusCode::MOVED_PERMANENTLY,
+            format!("{mount_prefix}{path}"),
+        )
+    };
+    // add equivalent routes on `/index.html` pages
+    if let Some(norm_path) = rel_path.strip_suffix("/index.html") {
+        let with_slash = format!("{norm_path}/");
+        if norm_path.is_empty() {
+            // a configured root document takes over `/` from the root `index.html`
+            if !has_root_document {
+                router = router.route(&with_slash, get_file.clone());
+            }
+        } else {
+            router = match config.trailing_slash {
+                TrailingSlash::Preserve => router
+                    .route(norm_path, get_file.clone())
+                    .route(&with_slash, get_file.clone()),
+                TrailingSlash::RedirectToNoSlash => router
+                    .route(norm_path, get_file.clone())
+                    .route(&with_slash, redirect(norm_path)),
+                TrailingSlash::RedirectToSlash => router
+                    .route(norm_path, redirect(&with_slash))
+                    .route(&with_slash, get_file.clone()),
+            };
+        }
+    } else if config.trailing_slash != TrailingSlash::Preserve {
+        // files never have a trailing slash
+        router = router.route(&format!("{rel_path}/"), redirect(&rel_path));
+    }
+    if is_root_document {
+        router = router.route("/", get_file.clone());
+    }
+    router.route(&rel_path, get_file)
+}
+
+fn file_route(served_file: Arc<ServedFile>, config: &Config) -> MethodRouter {
+    let max_clock_skew = Duration::from_secs(config.max_clock_skew_secs);
+    get(
+        async move |accept_encoding, if_none_match, if_modified_since, range| {
+            let if_modified_since = clamp_if_modified_since(if_modified_since, max_clock_skew);
+            serve_file(
+                accept_encoding,
+                if_none_match,
+                if_modified_since,
+                range,
+                served_file,
+            )
+            .await
+        },
+    )
+}
+
+/// Serves a single-page app's `index` for any path that doesn't look like an asset
+fn spa_route(
+    index: Arc<ServedFile>,
+    not_found_page: Option<Arc<ServedFile>>,
+    config: &Config,
+) -> MethodRouter {
+    let max_clock_skew = Duration::from_secs(config.max_clock_skew_secs);
+    // the wildcard only stands in for unknown paths, so other methods are still not found instead
+    // of not allowed
+    let other_methods = {
+        let not_found_page = not_found_page.clone();
+        async move |accept_encoding, if_none_match| {
+            status_code_page(
+                not_found_page.as_deref(),
+                StatusCode::NOT_FOUND,
+                accept_encoding,
+                if_none_match,
+            )
+        }
+    };
+    get(
+        async move |uri: Uri, accept_encoding, if_none_match, if_modified_since| {
+            let last_segment = uri.path().rsplit('/').next().unwrap_or_default();
+            if last_segment.contains('.') {
+                return status_code_page(
+                    not_found_page.as_deref(),
+                    StatusCode::NOT_FOUND,
+                    accept_encoding,
+                    if_none_match,
+                );
+            }
+            let if_modified_since = clamp_if_modified_since(if_modified_since, max_clock_skew);
+            // ranges only apply to data files which an `index.html` never is
+            serve_file(
+                accept_encoding,
+                if_none_match,
+                if_modified_since,
+                None,
+                index,
+            )
+            .await
+        },
+    )
+    .fallback(other_methods)
+}
+
+fn log_startup_summary(summary: &StartupSummary, mut files: Vec<(&str, ContentType, usize)>) {
+    let mut type_counts = BTreeMap::<_, usize>::new();
+    for (_, ty, _) in &files {
+        *type_counts.entry(ty).or_default() += 1;
+    }
+    let type_counts = type_counts
+        .into_iter()
+        .map(|(ty, count)| format!("{}: {count}", ty.name()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    tracing::info!(target: STARTUP_SUMMARY_TARGET, %type_counts, "Loaded content types");
+
+    let Some(largest_files) = summary.largest_files else {
+        return;
+    };
+    files.sort_unstable_by(|(_, _, a), (_, _, b)| b.cmp(a));
+    for (rank, (rel_path, _, in_memory_size)) in files.iter().take(largest_files).enumerate() {
+        tracing::info!(
+            target: STARTUP_SUMMARY_TARGET,
+            rank = rank + 1,
+            %rel_path,
+            in_memory_size = %disp::HumanBytes(*in_memory_size),
+            "Large file",
+        );
+    }
+}
 
-    router
-        .fallback(async move |encoding| {
-            status_code_page(not_found_page.as_deref(), StatusCode::NOT_FOUND, encoding)
+/// Loads a single file returning it along with how long loading took
+///
+/// Files that aren't a served type are skipped silently while ones that fail to load get logged
+fn load_file(path: &Path, rel_path: &str, config: &Config) -> Option<(ServedFile, Duration)> {
+    let start = Instant::now();
+    let loaded = ServedFile::load(
+        path,
+        config.content_type_for(rel_path)?,
+        config.compression_for(rel_path),
+        config.weak_e_tag_min_size,
+    );
+    let served_file = match loaded {
+        Ok(served_file) => served_file,
+        Err(err) => {
+            tracing::warn!(%rel_path, %err, "Skipping file that failed to load");
+            return None;
+        }
+    };
+    let cache_scope = config.cache_scope_for(rel_path, served_file.content_type());
+    let served_file = served_file.with_cache_scope(cache_scope);
+    Some((served_file, start.elapsed()))
+}
+
+/// Walks all of the files to serve yielding their full path along with their `/`-separated path
+/// relative to `dir`
+///
+/// Hidden files and directories (like `.well-known/`) are walked too. Entries within a directory
+/// are walked sorted by file name, so that loading and route registration happens in the same
+/// order across runs and platforms. A single file is walked as if it were the only file in its
+/// parent directory
+pub(crate) fn walk(dir: &Path) -> impl Iterator<Item = (PathBuf, String)> {
+    let root = match dir.parent() {
+        Some(parent) if dir.is_file() => parent,
+        _ => dir,
+    };
+    let config_path = root.join(Config::FILE_NAME);
+    WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(move |res| {
+            let entry = res.ok()?;
+            let path = entry.into_path();
+            if !path.is_file() || path == config_path {
+                return None;
+            }
+            let rel_path = path
+                .strip_prefix(root)
+                .unwrap()
+                .components()
+                .map(|comp| comp.as_os_str().to_str().unwrap())
+                .collect::<Vec<_>>()
+                .join("/");
+            Some((path, rel_path))
         })
-        .layer(
-            // NOTE: when you add a fallible middleware here make sure that you handle the error in
-            // `handle_middleware_error`
-            ServiceBuilder::new()
-                .layer(HandleErrorLayer::new(middleware_error_w_state))
-                // TODO: allow customizing this value
-                .timeout(Duration::from_secs(60))
-                .load_shed()
-                .layer(RecorderLayer::spawn()),
+}
+
+/// Stamps every response with when it was generated as required of origin servers
+async fn set_date(req: Request, next: Next) -> Response {
+    let mut resp = next.run(req).await;
+    let date = disp::HttpDate(SystemTime::now()).to_string();
+    resp.headers_mut().insert(
+        header::DATE,
+        date.try_into().expect("http dates are valid header values"),
+    );
+    resp
+}
+
+async fn reject_probes(State(probes): State<Arc<Probes>>, req: Request, next: Next) -> Response {
+    // scanners can send malformed paths too
+    let is_probe =
+        normalized_path(req.uri().path()).is_some_and(|path| probes.paths.is_match(&path));
+    if is_probe {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = probes.status;
+        resp
+    } else {
+        next.run(req).await
+    }
+}
+
+/// Rejects paths that are malformed (see [`is_malformed_path`]) with a `400 Bad Request`
+async fn reject_malformed_paths(
+    State(status_pages): State<StatusPages>,
+    accept_encoding: AcceptEncoding,
+    if_none_match: Option<IfNoneMatch>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if is_malformed_path(req.uri().path()) {
+        let status = StatusCode::BAD_REQUEST;
+        status_code_page(
+            status_pages.get(&status),
+            status,
+            accept_encoding,
+            if_none_match,
         )
+    } else {
+        next.run(req).await
+    }
+}
+
+/// Rejects requests with an `Expect` that can't be met (`417 Expectation Failed`) or a
+/// `Transfer-Encoding` other than `chunked` (`501 Not Implemented`)
+///
+/// `100-continue` is the only expectation there is, and the server sends the `100 Continue` on its
+/// own if the body ever gets read
+async fn reject_unsupported_headers(
+    State(status_pages): State<StatusPages>,
+    accept_encoding: AcceptEncoding,
+    if_none_match: Option<IfNoneMatch>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let headers = req.headers();
+    let unmet_expectation = headers.get_all(header::EXPECT).iter().any(|expect| {
+        !expect
+            .to_str()
+            .is_ok_and(|expect| expect.trim().eq_ignore_ascii_case("100-continue"))
+    });
+    let unknown_transfer_coding =
+        headers
+            .get_all(header::TRANSFER_ENCODING)
+            .iter()
+            .any(|codings| {
+                codings.to_str().map_or(true, |codings| {
+                    codings
+                        .split(',')
+                        .any(|coding| !coding.trim().eq_ignore_ascii_case("chunked"))
+                })
+            });
+    let status = if unmet_expectation {
+        StatusCode::EXPECTATION_FAILED
+    } else if unknown_transfer_coding {
+        StatusCode::NOT_IMPLEMENTED
+    } else {
+        return next.run(req).await;
+    };
+    status_code_page(
+        status_pages.get(&status),
+        status,
+        accept_encoding,
+        if_none_match,
+    )
+}
+
+/// Whether the percent-decoded path is invalid UTF-8, contains control characters, or has broken
+/// percent-encoding
+///
+/// None of these could ever match a file, so they're typically from scanners
+fn is_malformed_path(path: &str) -> bool {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next(), bytes.next()];
+            let Some(byte) = hex
+                .into_iter()
+                .map(|digit| char::from(digit?).to_digit(16))
+                .try_fold(0, |acc, digit| Some(acc * 16 + digit?))
+            else {
+                return true;
+            };
+            decoded.push(byte as u8);
+        } else {
+            decoded.push(byte);
+        }
+    }
+
+    match String::from_utf8(decoded) {
+        Ok(decoded) => decoded.chars().any(char::is_control),
+        Err(_) => true,
+    }
+}
+
+/// Collapses repeated slashes and resolves dot-segments in the request's path before routing
+///
+/// Paths that would traverse above the root get rejected with a `400 Bad Request`
+async fn normalize_path(
+    State(status_pages): State<StatusPages>,
+    accept_encoding: AcceptEncoding,
+    if_none_match: Option<IfNoneMatch>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let Some(norm_path) = normalized_path(req.uri().path()) else {
+        let status = StatusCode::BAD_REQUEST;
+        return status_code_page(
+            status_pages.get(&status),
+            status,
+            accept_encoding,
+            if_none_match,
+        );
+    };
+
+    if norm_path != req.uri().path() {
+        set_path(&mut req, &norm_path);
+    }
+
+    next.run(req).await
+}
+
+/// Routes requests under the mount prefix as if it wasn't there while anything outside of it is
+/// not found
+async fn strip_mount_prefix(
+    State((mount_prefix, status_pages)): State<(Arc<str>, StatusPages)>,
+    accept_encoding: AcceptEncoding,
+    if_none_match: Option<IfNoneMatch>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if mount_prefix.is_empty() {
+        return next.run(req).await;
+    }
+
+    match req.uri().path().strip_prefix(&*mount_prefix) {
+        Some("") => set_path(&mut req, "/"),
+        Some(rest) if rest.starts_with('/') => {
+            let rest = rest.to_owned();
+            set_path(&mut req, &rest);
+        }
+        _ => {
+            let status = StatusCode::NOT_FOUND;
+            return status_code_page(
+                status_pages.get(&status),
+                status,
+                accept_encoding,
+                if_none_match,
+            );
+        }
+    }
+
+    next.run(req).await
+}
+
+/// Swaps out the request's path while keeping its query
+fn set_path(req: &mut Request, path: &str) {
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_owned(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(
+        path_and_query
+            .parse()
+            .expect("only ever a part of an already valid path"),
+    );
+    *req.uri_mut() = Uri::from_parts(parts).expect("only the path was changed");
+}
+
+/// Whether `c` can appear as is in a path segment (RFC 3986's `pchar` minus the `%` of escapes)
+fn is_url_safe(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "-._~!$&'()*+,;=:@/".contains(c)
+}
+
+/// Percent-encodes everything that isn't URL-safe in a `/`-separated path
+fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for c in path.chars() {
+        if is_url_safe(c) {
+            encoded.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("%{byte:02X}"));
+            }
+        }
+    }
+    encoded
+}
+
+/// Returns `None` if the path escapes the root
+fn normalized_path(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/') {
+        // what's left _after_ a dot-segment is a directory, so it keeps its trailing slash
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut norm_path = String::with_capacity(path.len());
+    for segment in &segments {
+        norm_path.push('/');
+        norm_path.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        norm_path.push('/');
+    }
+    Some(norm_path)
 }
 
 async fn handle_middleware_error(
-    status_pages: Arc<BTreeMap<StatusCode, ServedFile>>,
-    encoding: Encoding,
+    status_pages: StatusPages,
+    retry_after_secs: u64,
+    accept_encoding: AcceptEncoding,
+    if_none_match: Option<IfNoneMatch>,
     err: BoxError,
 ) -> Response {
     let status = if err.is::<tower::load_shed::error::Overloaded>() {
@@ -127,27 +903,151 @@ async fn handle_middleware_error(
         tracing::warn!(%err, "Unhandled middleware error");
         StatusCode::INTERNAL_SERVER_ERROR
     };
-    status_code_page(status_pages.get(&status), status, encoding)
+    let mut resp = status_code_page(
+        status_pages.get(&status),
+        status,
+        accept_encoding,
+        if_none_match,
+    );
+    if matches!(
+        status,
+        StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS
+    ) {
+        // let well-behaved clients know how long to back off for
+        resp.headers_mut()
+            .insert(header::RETRY_AFTER, retry_after_secs.into());
+    }
+    resp
 }
 
-fn status_code_page(page: Option<&ServedFile>, status: StatusCode, encoding: Encoding) -> Response {
+fn status_code_page(
+    page: Option<&ServedFile>,
+    status: StatusCode,
+    accept_encoding: AcceptEncoding,
+    if_none_match: Option<IfNoneMatch>,
+) -> Response {
     let mut resp = match page {
-        Some(file) => file.to_response(encoding, None),
+        // revalidating by date is only for actual content
+        Some(file) => file.to_response(accept_encoding, if_none_match, None, None),
         None => Response::new(Body::from(status.to_string())),
     };
 
-    *resp.status_mut() = status;
+    // a successful revalidation still lets the client know that its copy is good to use
+    if resp.status() != StatusCode::NOT_MODIFIED {
+        *resp.status_mut() = status;
+    }
     // it's a status code page, so we don't know what content we would return
     resp.headers_mut().remove(header::ACCEPT_ENCODING);
-    resp.headers_mut().remove(header::CACHE_CONTROL);
+    // although pages that must never be stored still have to say so
+    if page.is_none_or(|file| file.cache_scope() != CacheScope::NoStore) {
+        resp.headers_mut().remove(header::CACHE_CONTROL);
+    }
+    resp.headers_mut().remove(header::LAST_MODIFIED);
     resp
 }
 
+fn redirect_to(status: StatusCode, path: String) -> MethodRouter {
+    get(async move |uri: Uri| {
+        // keep the query around, so that nothing gets lost in the redirect
+        let location = match uri.query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path,
+        };
+        Response::builder()
+            .status(status)
+            .header(header::LOCATION, location)
+            .body(Body::empty())
+            .unwrap()
+    })
+}
+
 async fn serve_file(
-    encoding: Encoding,
+    accept_encoding: AcceptEncoding,
     if_none_match: Option<IfNoneMatch>,
+    if_modified_since: Option<IfModifiedSince>,
+    range: Option<Range>,
     // TODO: could clone and consume the file directly instead of wrapping it in a `Arc`
     file: Arc<ServedFile>,
 ) -> Response {
-    file.to_response(encoding, if_none_match)
+    file.to_response(accept_encoding, if_none_match, if_modified_since, range)
+}
+
+/// Drops `If-Modified-Since` dates that are further in the future than `max_clock_skew`
+///
+/// Nothing can have been modified since a date that hasn't happened yet, so otherwise a
+/// misbehaving client would keep getting `304`s for stale content
+fn clamp_if_modified_since(
+    if_modified_since: Option<IfModifiedSince>,
+    max_clock_skew: Duration,
+) -> Option<IfModifiedSince> {
+    let IfModifiedSince(since) = if_modified_since?;
+    if since > SystemTime::now() + max_clock_skew {
+        tracing::debug!(since = %disp::HttpDate(since), "Ignoring future If-Modified-Since");
+        None
+    } else {
+        Some(IfModifiedSince(since))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_encoded_paths() {
+        assert_eq!(
+            percent_encode_path("posts/hello-world"),
+            "posts/hello-world"
+        );
+        assert_eq!(
+            percent_encode_path("c# and f#.html"),
+            "c%23%20and%20f%23.html"
+        );
+        assert_eq!(percent_encode_path("100%.txt"), "100%25.txt");
+        assert_eq!(percent_encode_path("why?.html"), "why%3F.html");
+        assert_eq!(percent_encode_path("café.html"), "caf%C3%A9.html");
+        assert_eq!(percent_encode_path("{id}.html"), "%7Bid%7D.html");
+    }
+
+    #[test]
+    fn malformed_paths() {
+        let table = [
+            ("/", false),
+            ("/posts/hello-world/", false),
+            ("/caf%C3%A9.html", false),
+            ("/100%25", false),
+            ("/%00", true),
+            ("/posts/%0a", true),
+            ("/%7F", true),
+            ("/%ff%fe", true),
+            ("/%e2%28%a1", true),
+            ("/%zz", true),
+            ("/%4", true),
+        ];
+        for (path, expected) in table {
+            assert_eq!(is_malformed_path(path), expected, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn overloaded_sets_retry_after() {
+        let overloaded = Box::new(tower::load_shed::error::Overloaded::new());
+        let resp = handle_middleware_error(
+            Arc::default(),
+            7,
+            AcceptEncoding::default(),
+            None,
+            overloaded,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "7");
+
+        let elapsed = Box::new(tower::timeout::error::Elapsed::new());
+        let resp =
+            handle_middleware_error(Arc::default(), 7, AcceptEncoding::default(), None, elapsed)
+                .await;
+        assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+        assert!(!resp.headers().contains_key(header::RETRY_AFTER));
+    }
 }
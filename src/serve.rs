@@ -0,0 +1,271 @@
+use std::{
+    future::{self, Future},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    Router,
+    serve::{Listener, ListenerExt},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
+
+/// A server running in the background from [`serve()`]
+pub struct Server<A = SocketAddr> {
+    local_addr: A,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl<A: Clone> Server<A> {
+    /// The address that the server is listening on. Handy when bound to port 0
+    pub fn local_addr(&self) -> A {
+        self.local_addr.clone()
+    }
+
+    /// Resolves once the server has stopped
+    ///
+    /// After a shutdown this waits for in-flight requests to finish, or for the drain timeout to
+    /// force close whatever is left
+    pub async fn wait(self) -> io::Result<()> {
+        self.task.await.map_err(io::Error::other)?
+    }
+}
+
+/// Begins gracefully shutting down the matching [`Server`]
+///
+/// Merely dropping the handle leaves the server running
+pub struct ShutdownHandle(oneshot::Sender<()>);
+
+impl ShutdownHandle {
+    pub fn shutdown(self) {
+        // the server already stopping on its own is fine
+        let _ = self.0.send(());
+    }
+}
+
+/// Serves `app` on `listener` in the background until shutdown
+///
+/// Once shut down, connections get `drain_timeout` to finish up before they're forcibly closed.
+/// Unlike `axum::serve()` this also exposes client addresses to the middleware
+pub fn serve(
+    listener: TcpListener,
+    app: Router,
+    drain_timeout: Duration,
+) -> io::Result<(Server, ShutdownHandle)> {
+    let local_addr = listener.local_addr()?;
+    let connections = Connections::default();
+    // a no-op tap gets us `axum`'s connection info impl for wrapped listeners
+    let listener = connections.track(listener).tap_io(|_| {});
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    Ok(spawn(local_addr, connections, drain_timeout, |shutdown| {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown)
+            .into_future()
+    }))
+}
+
+/// Like [`serve()`], but over a unix domain socket where there's no client address
+#[cfg(unix)]
+pub fn serve_unix(
+    listener: tokio::net::UnixListener,
+    app: Router,
+    drain_timeout: Duration,
+) -> io::Result<(Server<tokio::net::unix::SocketAddr>, ShutdownHandle)> {
+    let local_addr = listener.local_addr()?;
+    let connections = Connections::default();
+    let listener = connections.track(listener);
+    Ok(spawn(local_addr, connections, drain_timeout, |shutdown| {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown)
+            .into_future()
+    }))
+}
+
+type Shutdown = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Spawns the future from `serve_with_shutdown` which must begin a graceful shutdown once the
+/// passed in future resolves
+fn spawn<A, F>(
+    local_addr: A,
+    connections: Connections,
+    drain_timeout: Duration,
+    serve_with_shutdown: impl FnOnce(Shutdown) -> F,
+) -> (Server<A>, ShutdownHandle)
+where
+    F: Future<Output = io::Result<()>> + Send + 'static,
+{
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let (started_tx, started_rx) = oneshot::channel();
+    let shutdown = Box::pin(async move {
+        if shutdown_rx.await.is_err() {
+            // the handle was dropped without shutting down
+            future::pending::<()>().await;
+        }
+        let _ = started_tx.send(());
+    });
+    let serving = serve_with_shutdown(shutdown);
+    let task =
+        tokio::spawn(async move { drain(serving, started_rx, &connections, drain_timeout).await });
+    (Server { local_addr, task }, ShutdownHandle(shutdown_tx))
+}
+
+/// Runs `serving` to completion force closing any connections still open `drain_timeout` after
+/// shutdown `started`
+async fn drain(
+    serving: impl Future<Output = io::Result<()>>,
+    started: oneshot::Receiver<()>,
+    connections: &Connections,
+    drain_timeout: Duration,
+) -> io::Result<()> {
+    tokio::pin!(serving);
+    tokio::select! {
+        res = &mut serving => return res,
+        _ = started => {}
+    }
+    match tokio::time::timeout(drain_timeout, &mut serving).await {
+        Ok(res) => res,
+        Err(_) => {
+            tracing::warn!(
+                force_closed = connections.open(),
+                "Force closing connections that outlived the drain timeout",
+            );
+            connections.close_all();
+            serving.await
+        }
+    }
+}
+
+/// Keeps track of open connections, so that lingering ones can be closed
+#[derive(Clone)]
+struct Connections {
+    open: Arc<AtomicUsize>,
+    closed: watch::Sender<bool>,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Self {
+            open: Arc::default(),
+            closed: watch::Sender::new(false),
+        }
+    }
+}
+
+impl Connections {
+    fn track<L: Listener>(&self, listener: L) -> TrackedListener<L> {
+        TrackedListener {
+            inner: listener,
+            connections: self.clone(),
+        }
+    }
+
+    fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    fn close_all(&self) {
+        self.closed.send_replace(true);
+    }
+}
+
+struct TrackedListener<L> {
+    inner: L,
+    connections: Connections,
+}
+
+impl<L: Listener> Listener for TrackedListener<L> {
+    type Io = TrackedIo<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (io, addr) = self.inner.accept().await;
+        let Connections { open, closed } = &self.connections;
+        open.fetch_add(1, Ordering::Relaxed);
+        let mut closed = closed.subscribe();
+        let io = TrackedIo {
+            inner: io,
+            open: Arc::clone(open),
+            closed: Box::pin(async move {
+                let _ = closed.wait_for(|&closed| closed).await;
+            }),
+        };
+        (io, addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// A connection that errors out once it gets closed
+struct TrackedIo<Io> {
+    inner: Io,
+    open: Arc<AtomicUsize>,
+    closed: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl<Io> TrackedIo<Io> {
+    /// Polling this before any IO also registers for a wakeup on close, so that even idle
+    /// connections get closed
+    fn poll_closed(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        self.closed
+            .as_mut()
+            .poll(cx)
+            .map(|()| io::ErrorKind::ConnectionAborted.into())
+    }
+}
+
+impl<Io> Drop for TrackedIo<Io> {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<Io: AsyncRead + Unpin> AsyncRead for TrackedIo<Io> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Poll::Ready(err) = self.poll_closed(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<Io: AsyncWrite + Unpin> AsyncWrite for TrackedIo<Io> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(err) = self.poll_closed(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Poll::Ready(err) = self.poll_closed(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
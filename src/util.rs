@@ -1,4 +1,8 @@
-use std::{mem::size_of, sync::Arc};
+use std::{
+    mem::size_of,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use axum::{body::Bytes, http::HeaderValue};
 
@@ -26,6 +30,193 @@ impl TotalSize for Bytes {
     }
 }
 
+impl<T: TotalSize> TotalSize for Option<T> {
+    fn total_size(&self) -> usize {
+        match self {
+            // any space not covered by the niche
+            Some(inner) => size_of::<Self>() - size_of::<T>() + inner.total_size(),
+            None => size_of::<Self>(),
+        }
+    }
+}
+
+pub const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+pub const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A [`SystemTime`] broken down into its UTC calendar date and time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UtcDateTime {
+    pub year: i64,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millis: u16,
+    /// 0-6 starting from Monday
+    pub weekday: u8,
+}
+
+impl From<SystemTime> for UtcDateTime {
+    fn from(time: SystemTime) -> Self {
+        // times before the epoch aren't something that we ever deal with
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs() as i64;
+        let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+        // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: (secs_of_day / 3_600) as u8,
+            minute: (secs_of_day % 3_600 / 60) as u8,
+            second: (secs_of_day % 60) as u8,
+            millis: since_epoch.subsec_millis() as u16,
+            // the epoch was on a thursday
+            weekday: (days + 3).rem_euclid(7) as u8,
+        }
+    }
+}
+
+impl UtcDateTime {
+    /// Returns `None` for anything before the epoch
+    ///
+    /// The weekday is ignored as that's fully determined by the date
+    pub fn to_system_time(self) -> Option<SystemTime> {
+        let Self {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            millis,
+            weekday: _,
+        } = self;
+
+        // https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+        let year = year - i64::from(month <= 2);
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let mp = (i64::from(month) + 9) % 12;
+        let day_of_year = (153 * mp + 2) / 5 + i64::from(day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        let secs =
+            days * 86_400 + i64::from(hour) * 3_600 + i64::from(minute) * 60 + i64::from(second);
+        let since_epoch =
+            Duration::from_secs(secs.try_into().ok()?) + Duration::from_millis(millis.into());
+        Some(UNIX_EPOCH + since_epoch)
+    }
+}
+
+/// Parses an HTTP-date in any of the IMF-fixdate, RFC 850, or asctime formats
+///
+/// e.g. `Sun, 06 Nov 1994 08:49:37 GMT`, `Sunday, 06-Nov-94 08:49:37 GMT`, or
+/// `Sun Nov  6 08:49:37 1994` respectively
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    fn month(s: &str) -> Option<u8> {
+        let idx = MONTHS.iter().position(|month| *month == s)?;
+        Some(idx as u8 + 1)
+    }
+
+    fn time(s: &str) -> Option<(u8, u8, u8)> {
+        let mut parts = s.split(':');
+        let mut next = |max| {
+            let part = parts.next().filter(|part| part.len() == 2)?;
+            part.parse().ok().filter(|num| *num <= max)
+        };
+        // allow for leap seconds
+        let hms = (next(23)?, next(59)?, next(60)?);
+        parts.next().is_none().then_some(hms)
+    }
+
+    fn two_digit_year(s: &str) -> Option<i64> {
+        let year: i64 = s.parse().ok()?;
+        // RFC 9110: a two digit year more than 50 years in the future is in the past century
+        let this_year = UtcDateTime::from(SystemTime::now()).year;
+        let century = this_year - this_year % 100;
+        let year = century + year;
+        Some(if year > this_year + 50 {
+            year - 100
+        } else {
+            year
+        })
+    }
+
+    let (year, month, day, (hour, minute, second)) = match s.split_once(", ") {
+        Some((_weekday, rest)) => match rest.split(' ').collect::<Vec<_>>()[..] {
+            // IMF-fixdate
+            [day, mon, year, hms, "GMT"] if day.len() == 2 && year.len() == 4 => (
+                year.parse().ok()?,
+                month(mon)?,
+                day.parse().ok()?,
+                time(hms)?,
+            ),
+            // RFC 850
+            [date, hms, "GMT"] => match date.split('-').collect::<Vec<_>>()[..] {
+                [day, mon, year] if day.len() == 2 && year.len() == 2 => (
+                    two_digit_year(year)?,
+                    month(mon)?,
+                    day.parse().ok()?,
+                    time(hms)?,
+                ),
+                _ => return None,
+            },
+            _ => return None,
+        },
+        // asctime
+        None => match s.split_whitespace().collect::<Vec<_>>()[..] {
+            [_weekday, mon, day, hms, year] if year.len() == 4 => (
+                year.parse().ok()?,
+                month(mon)?,
+                day.parse().ok()?,
+                time(hms)?,
+            ),
+            _ => return None,
+        },
+    };
+
+    let days_in_month = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+
+    UtcDateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        millis: 0,
+        weekday: 0,
+    }
+    .to_system_time()
+}
+
 pub mod disp {
     use std::{
         fmt,
@@ -48,6 +239,32 @@ pub mod disp {
         }
     }
 
+    /// Formats as an IMF-fixdate e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+    ///
+    /// Any sub-second precision gets truncated
+    pub struct HttpDate(pub SystemTime);
+
+    impl fmt::Display for HttpDate {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let super::UtcDateTime {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                weekday,
+                ..
+            } = self.0.into();
+            let weekday = super::WEEKDAYS[usize::from(weekday)];
+            let month = super::MONTHS[usize::from(month) - 1];
+            write!(
+                f,
+                "{weekday}, {day:02} {month} {year:04} {hour:02}:{minute:02}:{second:02} GMT"
+            )
+        }
+    }
+
     pub struct HumanBytes(pub usize);
 
     impl fmt::Display for HumanBytes {
@@ -56,6 +273,8 @@ pub mod disp {
                 B,
                 Kib,
                 Mib,
+                Gib,
+                Tib,
             }
 
             impl fmt::Display for Suffix {
@@ -64,23 +283,112 @@ pub mod disp {
                         Self::B => "B",
                         Self::Kib => "KiB",
                         Self::Mib => "MiB",
+                        Self::Gib => "GiB",
+                        Self::Tib => "TiB",
                     };
                     f.write_str(s)
                 }
             }
 
-            let mut size = self.0 as f32;
+            const STEP: f64 = 1_024.0;
+
+            let mut size = self.0 as f64;
             let mut suffix = Suffix::B;
-            while size > 1_000.0 {
+            while size >= STEP {
                 suffix = match suffix {
                     Suffix::B => Suffix::Kib,
                     Suffix::Kib => Suffix::Mib,
-                    Suffix::Mib => break,
+                    Suffix::Mib => Suffix::Gib,
+                    Suffix::Gib => Suffix::Tib,
+                    Suffix::Tib => break,
                 };
-                size /= 1_024.0;
+                size /= STEP;
             }
 
             write!(f, "{size:.2} {suffix}")
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the canonical example from the spec
+    const EXAMPLE_SECS: u64 = 784_111_777;
+
+    #[test]
+    fn human_bytes() {
+        let table = [
+            (0, "0.00 B"),
+            (1_000, "1000.00 B"),
+            (1_023, "1023.00 B"),
+            (1_024, "1.00 KiB"),
+            (1_536, "1.50 KiB"),
+            (1_048_575, "1024.00 KiB"),
+            (1_048_576, "1.00 MiB"),
+            (5 * 1_024 * 1_024 * 1_024, "5.00 GiB"),
+            (1_024 * 1_024 * 1_024 * 1_024, "1.00 TiB"),
+            (2_048 * 1_024 * 1_024 * 1_024 * 1_024, "2048.00 TiB"),
+        ];
+        for (bytes, expected) in table {
+            assert_eq!(
+                disp::HumanBytes(bytes).to_string(),
+                expected,
+                "bytes: {bytes}"
+            );
+        }
+    }
+
+    #[test]
+    fn http_date_formats() {
+        let time = UNIX_EPOCH + Duration::from_secs(EXAMPLE_SECS);
+        let formatted = disp::HttpDate(time).to_string();
+        assert_eq!(formatted, "Sun, 06 Nov 1994 08:49:37 GMT");
+        // sub-second precision is dropped
+        let formatted = disp::HttpDate(time + Duration::from_millis(999)).to_string();
+        assert_eq!(formatted, "Sun, 06 Nov 1994 08:49:37 GMT");
+        let formatted = disp::HttpDate(UNIX_EPOCH).to_string();
+        assert_eq!(formatted, "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn http_date_parses_all_formats() {
+        let expected = UNIX_EPOCH + Duration::from_secs(EXAMPLE_SECS);
+        for date in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(parse_http_date(date), Some(expected), "date: {date}");
+        }
+    }
+
+    #[test]
+    fn http_date_round_trips() {
+        for secs in [0, 951_782_400, EXAMPLE_SECS, 1_709_208_000, 4_102_444_799] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            let formatted = disp::HttpDate(time).to_string();
+            assert_eq!(parse_http_date(&formatted), Some(time), "date: {formatted}");
+        }
+    }
+
+    #[test]
+    fn http_date_rejects_invalid() {
+        for date in [
+            "",
+            "Sun, 06 Nov 1994 08:49:37",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 6 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 31 Nov 1994 08:49:37 GMT",
+            "Sun, 29 Feb 1900 08:49:37 GMT",
+            "Sunday, 06-Nov-1994 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 94",
+            "Thu, 01 Jan 1969 00:00:00 GMT",
+        ] {
+            assert_eq!(parse_http_date(date), None, "date: {date}");
+        }
+    }
+}
@@ -1,21 +1,47 @@
-use std::{array, collections::BTreeMap, fmt, path::Path, sync::LazyLock};
+use std::{
+    array,
+    collections::BTreeMap,
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use a_blog_out_of_deep_space::router;
+use a_blog_out_of_deep_space::{
+    AccessLog, CacheScope, CacheScopes, Config, ContentType, Encoding, ExtraType, Limits,
+    LoadError, LogFormat, PathCacheScope, PathGlobs, PoorCompression, Probes, Robots, RobotsPolicy,
+    RootFallback, Throttle, TrailingSlash, UrlUnsafeNames, UserAgentPatterns, audit_compression,
+    router, router_with_recorder, serve,
+};
 use axum::{
     Router,
     body::Body,
-    extract::Request,
-    http::{HeaderValue, StatusCode, header},
+    extract::{ConnectInfo, Request},
+    http::{HeaderName, HeaderValue, Method, StatusCode, header},
     response::Response,
 };
 use tokio::task::JoinSet;
 use tower::{Service, ServiceExt};
 
+fn site_dir() -> PathBuf {
+    Path::new("tests").join("assets").join("site")
+}
+
 async fn call_test_server(req: Request) -> Response {
     // cache to avoid costly reinitialization
     static ROUTER: LazyLock<Router> =
-        LazyLock::new(|| router(Path::new("tests").join("assets").join("site")));
-    let mut router = ROUTER.clone();
+        LazyLock::new(|| router(site_dir(), Config::default()).unwrap());
+    call_router(ROUTER.clone(), req).await
+}
+
+/// like [`call_test_server()`], but with a custom config
+async fn call_configured_server(config: Config, req: Request) -> Response {
+    call_router(router(site_dir(), config).unwrap(), req).await
+}
+
+async fn call_router(mut router: Router, req: Request) -> Response {
     <_ as ServiceExt<Request>>::ready(&mut router)
         .await
         .unwrap()
@@ -83,7 +109,15 @@ impl SnapTextResp {
         let headers = resp
             .headers()
             .iter()
-            .map(|(n, v)| (n.as_str().to_owned(), v.to_str().unwrap().to_owned()))
+            .map(|(n, v)| {
+                // file modification times vary between checkouts and the date with every run
+                let v = if n == header::LAST_MODIFIED || n == header::DATE {
+                    "[http-date]"
+                } else {
+                    v.to_str().unwrap()
+                };
+                (n.as_str().to_owned(), v.to_owned())
+            })
             .collect();
         let body = body_string(resp.into_body()).await.unwrap();
         Self {
@@ -94,6 +128,223 @@ impl SnapTextResp {
     }
 }
 
+/// What a [`Case`]'s response body should look like
+enum ExpectedBody {
+    Any,
+    Empty,
+    StartsWith(&'static str),
+    Exact(Vec<u8>),
+}
+
+/// A single request along with what's expected of its response for table-driven tests
+///
+/// The expectations default to a `200 OK` with any headers and any body. See [`check_cases()`]
+struct Case {
+    req: Request,
+    status: StatusCode,
+    headers: Vec<(HeaderName, Option<HeaderValue>)>,
+    body: ExpectedBody,
+}
+
+impl Case {
+    fn new(method: Method, path: &str) -> Self {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        Self {
+            req,
+            status: StatusCode::OK,
+            headers: Vec::new(),
+            body: ExpectedBody::Any,
+        }
+    }
+
+    fn get(path: &str) -> Self {
+        Self::new(Method::GET, path)
+    }
+
+    fn head(path: &str) -> Self {
+        Self::new(Method::HEAD, path)
+    }
+
+    /// Sets a request header
+    fn header<V>(mut self, name: HeaderName, value: V) -> Self
+    where
+        V: TryInto<HeaderValue, Error: fmt::Debug>,
+    {
+        self.req
+            .headers_mut()
+            .insert(name, value.try_into().unwrap());
+        self
+    }
+
+    fn expect_status(self, status: StatusCode) -> Self {
+        Self { status, ..self }
+    }
+
+    fn expect_header<V>(mut self, name: HeaderName, value: V) -> Self
+    where
+        V: TryInto<HeaderValue, Error: fmt::Debug>,
+    {
+        self.headers.push((name, Some(value.try_into().unwrap())));
+        self
+    }
+
+    fn expect_no_header(mut self, name: HeaderName) -> Self {
+        self.headers.push((name, None));
+        self
+    }
+
+    fn expect_body(self, body: ExpectedBody) -> Self {
+        Self { body, ..self }
+    }
+}
+
+impl fmt::Display for Case {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.req.method(), self.req.uri())?;
+        for (name, value) in self.req.headers() {
+            write!(f, " [{name}: {}]", value.to_str().unwrap())?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs each of the `cases` against `app` checking all of their expectations
+async fn check_cases(app: Router, cases: impl IntoIterator<Item = Case>) {
+    for case in cases {
+        let desc = case.to_string();
+        let Case {
+            req,
+            status,
+            headers,
+            body,
+        } = case;
+        let resp = call_router(app.clone(), req).await;
+        assert_eq!(resp.status(), status, "{desc}");
+        for (name, expected) in headers {
+            assert_eq!(
+                resp.headers().get(&name),
+                expected.as_ref(),
+                "{desc} ({name})"
+            );
+        }
+        let resp_body = body_vec(resp.into_body()).await.unwrap();
+        match body {
+            ExpectedBody::Any => {}
+            ExpectedBody::Empty => assert!(resp_body.is_empty(), "{desc}"),
+            ExpectedBody::StartsWith(prefix) => {
+                let resp_body = String::from_utf8_lossy(&resp_body);
+                assert!(resp_body.starts_with(prefix), "{desc}: {resp_body}");
+            }
+            ExpectedBody::Exact(expected) => assert_eq!(resp_body, expected, "{desc}"),
+        }
+    }
+}
+
+/// conditional requests, `HEAD`, and encoding negotiation all together
+#[tokio::test]
+async fn conditional_and_negotiation_cases() {
+    let app = router(site_dir(), Config::default()).unwrap();
+    let resp = call_router(app.clone(), get_req("/robots.txt")).await;
+    let e_tag = resp.headers().get(header::ETAG).unwrap().to_owned();
+    let robots = std::fs::read(site_dir().join("robots.txt")).unwrap();
+    let weak_e_tag = format!("W/{}", e_tag.to_str().unwrap());
+
+    let cases = [
+        Case::get("/robots.txt")
+            .expect_header(header::ETAG, e_tag.clone())
+            .expect_no_header(header::CONTENT_ENCODING)
+            .expect_body(ExpectedBody::Exact(robots)),
+        Case::get("/robots.txt")
+            .header(header::IF_NONE_MATCH, e_tag.clone())
+            .expect_status(StatusCode::NOT_MODIFIED)
+            .expect_body(ExpectedBody::Empty),
+        Case::get("/robots.txt")
+            .header(header::IF_NONE_MATCH, weak_e_tag.as_str())
+            .expect_status(StatusCode::NOT_MODIFIED),
+        Case::get("/robots.txt")
+            .header(header::IF_NONE_MATCH, "\"nope\", *")
+            .expect_status(StatusCode::NOT_MODIFIED),
+        Case::get("/robots.txt")
+            .header(header::IF_NONE_MATCH, "\"nope\"")
+            .expect_header(header::ETAG, e_tag.clone())
+            .expect_body(ExpectedBody::StartsWith("User-agent: ")),
+        Case::head("/robots.txt")
+            .expect_header(header::ETAG, e_tag)
+            .expect_body(ExpectedBody::Empty),
+        // the client's preference order wins
+        Case::get("/sitemap.xml")
+            .header(header::ACCEPT_ENCODING, "gzip, br")
+            .expect_header(header::CONTENT_ENCODING, "gzip")
+            .expect_header(header::VARY, "accept-encoding"),
+        Case::get("/sitemap.xml")
+            .header(header::ACCEPT_ENCODING, "br, gzip")
+            .expect_header(header::CONTENT_ENCODING, "br"),
+        Case::get("/sitemap.xml")
+            .header(header::ACCEPT_ENCODING, "deflate, br")
+            .expect_header(header::CONTENT_ENCODING, "br"),
+        Case::get("/sitemap.xml")
+            .header(header::ACCEPT_ENCODING, "identity, br")
+            .expect_no_header(header::CONTENT_ENCODING)
+            .expect_body(ExpectedBody::StartsWith("<?xml")),
+        Case::get("/sitemap.xml")
+            .header(header::ACCEPT_ENCODING, "deflate")
+            .expect_no_header(header::CONTENT_ENCODING)
+            .expect_header(header::VARY, "accept-encoding"),
+        Case::get("/img/favicon.png")
+            .header(header::ACCEPT_ENCODING, "gzip, br")
+            .expect_no_header(header::CONTENT_ENCODING)
+            .expect_no_header(header::VARY),
+    ];
+    check_cases(app, cases).await;
+}
+
+/// `If-Modified-Since` revalidates off of `Last-Modified` while ignoring dates from the future
+#[tokio::test]
+async fn if_modified_since_cases() {
+    let app = router(site_dir(), Config::default()).unwrap();
+    let resp = call_router(app.clone(), get_req("/robots.txt")).await;
+    let last_modified = resp
+        .headers()
+        .get(header::LAST_MODIFIED)
+        .unwrap()
+        .to_owned();
+
+    let cases = [
+        Case::get("/robots.txt")
+            .header(header::IF_MODIFIED_SINCE, last_modified.clone())
+            .expect_status(StatusCode::NOT_MODIFIED)
+            .expect_body(ExpectedBody::Empty),
+        Case::get("/robots.txt")
+            .header(header::IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT")
+            .expect_header(header::LAST_MODIFIED, last_modified.clone())
+            .expect_body(ExpectedBody::StartsWith("User-agent: ")),
+        // the other formats work too
+        Case::get("/robots.txt")
+            .header(
+                header::IF_MODIFIED_SINCE,
+                "Thursday, 01-Jan-70 00:00:00 GMT",
+            )
+            .expect_status(StatusCode::OK),
+        // nothing can be modified since a date from the future
+        Case::get("/robots.txt")
+            .header(header::IF_MODIFIED_SINCE, "Fri, 01 Jan 2100 00:00:00 GMT")
+            .expect_status(StatusCode::OK),
+        Case::get("/robots.txt")
+            .header(header::IF_MODIFIED_SINCE, "not a date")
+            .expect_status(StatusCode::OK),
+        // `If-None-Match` takes precedence
+        Case::get("/robots.txt")
+            .header(header::IF_MODIFIED_SINCE, last_modified)
+            .header(header::IF_NONE_MATCH, "\"nope\"")
+            .expect_status(StatusCode::OK),
+    ];
+    check_cases(app, cases).await;
+}
+
 #[tokio::test]
 async fn sanity_root() {
     let req = get_req("/");
@@ -105,11 +356,14 @@ async fn sanity_root() {
         @r#"
         200 - OK
          accept-encoding: gzip, br
-           cache-control: max-age=300
+           cache-control: public, max-age=300
           content-length: 654
             content-type: text/html; charset=utf-8
+                    date: [http-date]
                     etag: "e2e7b1b46a3923e"
+           last-modified: [http-date]
                   server: a-blog-out-of-deep-space 0.1.0
+                    vary: accept-encoding
         ---
         <!doctype html>
         <html lang="en">
@@ -155,12 +409,1031 @@ async fn index_html_normalized() {
     }
 }
 
-/// status code pages in the root of the site aren't reachable
-///
-/// 408.html is not found depsite existing in the root of the repo
+/// malformed paths get normalized before looking up the file
+#[tokio::test]
+async fn malformed_paths_normalized() {
+    let expected = {
+        let resp = call_test_server(get_req("/posts/index.html")).await;
+        assert_resp_success(&resp);
+        body_string(resp.into_body()).await.unwrap()
+    };
+
+    let malformed_paths = &[
+        "//posts/index.html",
+        "/posts//index.html",
+        "/posts/./index.html",
+        "/./posts/index.html",
+        "/posts/hello-world/../index.html",
+        "/img/../posts/index.html",
+        "/posts/hello-world/..",
+        "/posts//",
+        "/posts/.",
+        "/posts/index.html?with=query",
+    ];
+    for path in malformed_paths {
+        let resp = call_test_server(get_req(path)).await;
+        assert_resp_success(&resp);
+        let body = body_string(resp.into_body()).await.unwrap();
+        assert_eq!(expected, body, "path: {path}");
+    }
+}
+
+/// paths that would traverse above the root of the site are rejected
+#[tokio::test]
+async fn path_traversal_rejected() {
+    let traversing_paths = &[
+        "/..",
+        "/../index.html",
+        "/posts/../../index.html",
+        "/a/../../b",
+    ];
+    for path in traversing_paths {
+        let resp = call_test_server(get_req(path)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "path: {path}");
+    }
+}
+
+/// a configured root document takes over `/` without hiding the root `index.html`
+#[tokio::test]
+async fn root_document() {
+    let config = || Config {
+        root_document: Some("home.html".into()),
+        ..Config::default()
+    };
+
+    let resp = call_configured_server(config(), get_req("/")).await;
+    assert_resp_success(&resp);
+    let body = body_string(resp.into_body()).await.unwrap();
+    assert!(body.contains("<h1>Home sweet home</h1>"), "{body}");
+
+    let resp = call_configured_server(config(), get_req("/index.html")).await;
+    assert_resp_success(&resp);
+    let body = body_string(resp.into_body()).await.unwrap();
+    assert!(body.contains("<h1>The base</h1>"), "{body}");
+
+    // and subdirectories still use their own index
+    let resp = call_configured_server(config(), get_req("/posts/")).await;
+    assert_resp_success(&resp);
+    let body = body_string(resp.into_body()).await.unwrap();
+    assert!(body.contains("<title>All the posts</title>"), "{body}");
+}
+
+#[tokio::test]
+async fn root_fallback() {
+    // a site without any root `index.html`
+    let dir = site_dir().join("img");
+    let resp = call_router(
+        router(dir.clone(), Config::default()).unwrap(),
+        get_req("/"),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let redirect = Config {
+        root_fallback: Some(RootFallback::Redirect("/favicon.png".into())),
+        ..Config::default()
+    };
+    let app = router(dir.clone(), redirect).unwrap();
+    let resp = call_router(app, get_req("/?utm=1")).await;
+    assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        resp.headers().get(header::LOCATION).unwrap(),
+        "/favicon.png?utm=1"
+    );
+
+    // the redirect stays under the mount prefix with exactly one slash between the two
+    for path in ["/favicon.png", "favicon.png"] {
+        let redirect = Config {
+            mount_prefix: "/blog/".into(),
+            root_fallback: Some(RootFallback::Redirect(path.into())),
+            ..Config::default()
+        };
+        let app = router(dir.clone(), redirect).unwrap();
+        let resp = call_router(app, get_req("/blog/")).await;
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT, "{path}");
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            "/blog/favicon.png",
+            "{path}",
+        );
+    }
+
+    let serve = || Config {
+        root_fallback: Some(RootFallback::Serve("favicon.png".into())),
+        ..Config::default()
+    };
+    let resp = call_router(router(dir, serve()).unwrap(), get_req("/")).await;
+    assert_resp_success(&resp);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/png"
+    );
+
+    // but it never takes over an actual root index
+    let resp = call_configured_server(serve(), get_req("/")).await;
+    assert_resp_success(&resp);
+    let body = body_string(resp.into_body()).await.unwrap();
+    assert!(body.contains("<h1>The base</h1>"), "{body}");
+}
+
+#[track_caller]
+fn assert_redirect(resp: &Response, location: &str) {
+    assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(resp.headers().get(header::LOCATION).unwrap(), location);
+}
+
+/// the trailing slash policy applies to both directories and files
+#[tokio::test]
+async fn trailing_slash_policy() {
+    let config = |trailing_slash| Config {
+        trailing_slash,
+        ..Config::default()
+    };
+
+    // preserve: everything stays where it is
+    let cases = [
+        ("/posts", StatusCode::OK),
+        ("/posts/", StatusCode::OK),
+        ("/posts/index.html", StatusCode::OK),
+        ("/robots.txt", StatusCode::OK),
+        ("/robots.txt/", StatusCode::NOT_FOUND),
+    ];
+    for (path, status) in cases {
+        let resp = call_configured_server(config(TrailingSlash::Preserve), get_req(path)).await;
+        assert_eq!(resp.status(), status, "path: {path}");
+    }
+
+    // redirect-to-no-slash
+    let app = router(site_dir(), config(TrailingSlash::RedirectToNoSlash)).unwrap();
+    let resp = call_router(app.clone(), get_req("/posts/?page=2")).await;
+    assert_redirect(&resp, "/posts?page=2");
+    let resp = call_router(app.clone(), get_req("/robots.txt/")).await;
+    assert_redirect(&resp, "/robots.txt");
+    for path in ["/", "/posts", "/posts/index.html", "/robots.txt"] {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_resp_success(&resp);
+    }
+
+    // redirect-to-slash
+    let app = router(site_dir(), config(TrailingSlash::RedirectToSlash)).unwrap();
+    let resp = call_router(app.clone(), get_req("/posts?page=2")).await;
+    assert_redirect(&resp, "/posts/?page=2");
+    let resp = call_router(app.clone(), get_req("/posts/hello-world")).await;
+    assert_redirect(&resp, "/posts/hello-world/");
+    let resp = call_router(app.clone(), get_req("/robots.txt/")).await;
+    assert_redirect(&resp, "/robots.txt");
+    for path in ["/", "/posts/", "/posts/index.html", "/robots.txt"] {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_resp_success(&resp);
+    }
+}
+
+/// expectations that can't be met and unknown transfer codings get rejected up front
+#[tokio::test]
+async fn unsupported_headers() {
+    let table = [
+        (header::EXPECT, "100-continue", StatusCode::OK),
+        (header::EXPECT, "100-Continue", StatusCode::OK),
+        (header::EXPECT, "200-ok", StatusCode::EXPECTATION_FAILED),
+        (header::TRANSFER_ENCODING, "chunked", StatusCode::OK),
+        (
+            header::TRANSFER_ENCODING,
+            "gzip, chunked",
+            StatusCode::NOT_IMPLEMENTED,
+        ),
+        (
+            header::TRANSFER_ENCODING,
+            "bogus",
+            StatusCode::NOT_IMPLEMENTED,
+        ),
+    ];
+    for (name, value, status) in table {
+        let req = Request::get("/robots.txt")
+            .header(&name, value)
+            .body(Body::empty())
+            .unwrap();
+        let resp = call_test_server(req).await;
+        assert_eq!(resp.status(), status, "{name}: {value}");
+    }
+}
+
+/// everything gets served under the mount prefix with the prefix itself acting like `/`
+#[tokio::test]
+async fn mount_prefix() {
+    let config = Config {
+        // slashes get normalized
+        mount_prefix: "blog/".into(),
+        trailing_slash: TrailingSlash::RedirectToSlash,
+        ..Config::default()
+    };
+    let app = router(site_dir(), config).unwrap();
+
+    let root = call_test_server(get_req("/")).await;
+    let root = body_vec(root.into_body()).await.unwrap();
+    for path in ["/blog", "/blog/", "/blog/index.html"] {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_resp_success(&resp);
+        assert_eq!(
+            body_vec(resp.into_body()).await.unwrap(),
+            root,
+            "path: {path}"
+        );
+    }
+    for path in ["/blog/robots.txt", "/blog//robots.txt", "/blog/posts/"] {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_resp_success(&resp);
+    }
+
+    // redirects stay under the prefix
+    let resp = call_router(app.clone(), get_req("/blog/posts?page=2")).await;
+    assert_redirect(&resp, "/blog/posts/?page=2");
+    let resp = call_router(app.clone(), get_req("/blog/robots.txt/")).await;
+    assert_redirect(&resp, "/blog/robots.txt");
+
+    // and anything outside of it is not found, status code page and all
+    let not_found = call_router(app.clone(), get_req("/blog/not-found")).await;
+    assert_eq!(not_found.status(), StatusCode::NOT_FOUND);
+    let not_found = body_vec(not_found.into_body()).await.unwrap();
+    for path in ["/", "/robots.txt", "/blogger", "/posts/"] {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND, "path: {path}");
+        assert_eq!(body_vec(resp.into_body()).await.unwrap(), not_found);
+    }
+}
+
+/// unknown paths under a single-page app's root get its `index.html` while assets still 404
+#[tokio::test]
+async fn spa_fallback() {
+    let config = Config {
+        // both spellings of the same root only register it once
+        spa_roots: vec!["/posts".into(), "posts/".into()],
+        ..Config::default()
+    };
+    let app = router(site_dir(), config).unwrap();
+
+    let index = call_router(app.clone(), get_req("/posts/index.html")).await;
+    let etag = index.headers().get(header::ETAG).unwrap().clone();
+    let index = body_vec(index.into_body()).await.unwrap();
+    for path in [
+        "/posts/some/deep/route",
+        "/posts/drafts/",
+        "/posts/search?q=rust",
+    ] {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{path}");
+        assert_eq!(resp.headers().get(header::ETAG), Some(&etag), "{path}");
+        assert_eq!(body_vec(resp.into_body()).await.unwrap(), index, "{path}");
+    }
+
+    // actual files still win
+    let resp = call_router(app.clone(), get_req("/posts/hello-world")).await;
+    assert_resp_success(&resp);
+    assert_ne!(body_vec(resp.into_body()).await.unwrap(), index);
+
+    // the fallback revalidates like the index itself
+    let mut req = get_req("/posts/some/deep/route");
+    req.headers_mut().insert(header::IF_NONE_MATCH, etag);
+    let resp = call_router(app.clone(), req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+    // while missing assets and anything outside of the root are still not found
+    for path in [
+        "/posts/main.js",
+        "/posts/img/missing.png",
+        "/elsewhere/route",
+    ] {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+
+    // other methods on unknown paths don't get promoted to `405 Method Not Allowed`
+    let req = Request::post("/posts/some/deep/route")
+        .body(Body::empty())
+        .unwrap();
+    let resp = call_router(app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body = body_vec(resp.into_body()).await.unwrap();
+    assert_eq!(
+        body,
+        std::fs::read(site_dir().join("_status/404.html")).unwrap()
+    );
+}
+
+/// binary content behind a compressible extension gets skipped instead of failing loading
+#[tokio::test]
+async fn non_utf8_text_is_skipped() {
+    let resp = call_test_server(get_req("/binary.txt")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = call_test_server(get_req("/robots.txt")).await;
+    assert_resp_success(&resp);
+
+    // without compression it never has to be text in the first place
+    let config = Config {
+        encodings: Vec::new(),
+        ..Config::default()
+    };
+    let resp = call_configured_server(config, get_req("/binary.txt")).await;
+    assert_resp_success(&resp);
+    let expected = std::fs::read(site_dir().join("binary.txt")).unwrap();
+    assert_eq!(body_vec(resp.into_body()).await.unwrap(), expected);
+}
+
+/// files over the per-file limit get skipped or abort loading entirely
+#[tokio::test]
+async fn file_size_limit() {
+    let config = |strict| Config {
+        limits: Limits {
+            // smaller than `index.html`, but larger than `robots.txt`
+            max_file_size: Some(128),
+            strict,
+            ..Limits::default()
+        },
+        ..Config::default()
+    };
+
+    let app = router(site_dir(), config(false)).unwrap();
+    let resp = call_router(app.clone(), get_req("/index.html")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = call_router(app, get_req("/robots.txt")).await;
+    assert_resp_success(&resp);
+
+    let err = router(site_dir(), config(true)).unwrap_err();
+    assert!(matches!(err, LoadError::FileTooLarge { .. }), "{err}");
+}
+
+/// loading halts once the total size limit is hit or aborts entirely
+#[tokio::test]
+async fn total_size_limit() {
+    let config = |strict| Config {
+        limits: Limits {
+            max_total_size: Some(1),
+            strict,
+            ..Limits::default()
+        },
+        ..Config::default()
+    };
+
+    let app = router(site_dir(), config(false)).unwrap();
+    for path in ["/", "/robots.txt", "/posts/"] {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND, "path: {path}");
+    }
+
+    let err = router(site_dir(), config(true)).unwrap_err();
+    assert!(matches!(err, LoadError::TotalTooLarge { .. }), "{err}");
+}
+
+/// files after the one that hits the total size limit never get added, no matter how many load in
+/// parallel
+#[tokio::test]
+async fn total_size_limit_parallel_loading() {
+    for load_threads in [1, 4] {
+        let config = Config {
+            load_threads: NonZeroUsize::new(load_threads),
+            limits: Limits {
+                // enough for the small files that sort before the large favicon
+                max_total_size: Some(10_000),
+                ..Limits::default()
+            },
+            ..Config::default()
+        };
+        let app = router(site_dir(), config).unwrap();
+        for (path, status) in [
+            ("/feed.rss", StatusCode::OK),
+            ("/img/favicon.png", StatusCode::NOT_FOUND),
+            ("/robots.txt", StatusCode::NOT_FOUND),
+        ] {
+            let resp = call_router(app.clone(), get_req(path)).await;
+            assert_eq!(
+                resp.status(),
+                status,
+                "path: {path}, threads: {load_threads}"
+            );
+        }
+    }
+}
+
+/// compressible files can be forced to be served uncompressed by path
+#[tokio::test]
+async fn incompressible_override() {
+    let req = || {
+        let mut req = get_req("/sitemap.xml");
+        req.headers_mut()
+            .insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        req
+    };
+
+    let resp = call_test_server(req()).await;
+    assert_eq!(
+        resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+        "gzip"
+    );
+
+    let config = Config {
+        incompressible: PathGlobs::new(["/*.xml"]).unwrap(),
+        ..Config::default()
+    };
+    let resp = call_configured_server(config, req()).await;
+    assert_resp_success(&resp);
+    assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+    let body = body_string(resp.into_body()).await.unwrap();
+    assert!(body.starts_with("<?xml"), "{body}");
+}
+
+#[tokio::test]
+async fn compressible_type_override() {
+    let config = || Config {
+        compressible_types: [(ContentType::Html, false)].into(),
+        ..Config::default()
+    };
+    let req = |path| {
+        let mut req = get_req(path);
+        req.headers_mut()
+            .insert(header::ACCEPT_ENCODING, HeaderValue::from_static("br"));
+        req
+    };
+
+    let resp = call_configured_server(config(), req("/")).await;
+    assert_resp_success(&resp);
+    assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+    assert!(!resp.headers().contains_key(header::VARY));
+    let body = body_string(resp.into_body()).await.unwrap();
+    assert!(body.contains("<h1>The base</h1>"), "{body}");
+
+    // while other types are still compressed
+    let resp = call_configured_server(config(), req("/sitemap.xml")).await;
+    assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+}
+
+#[tokio::test]
+async fn extra_types() {
+    let req = || {
+        let mut req = get_req("/captions.vtt");
+        req.headers_mut()
+            .insert(header::ACCEPT_ENCODING, HeaderValue::from_static("br"));
+        req
+    };
+    // unknown extensions aren't served by default
+    let resp = call_test_server(req()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let vtt = ExtraType {
+        mime: HeaderValue::from_static("text/vtt"),
+        compressible: true,
+        override_builtin: false,
+    };
+    let config = Config {
+        extra_types: [("vtt".into(), vtt)].into(),
+        ..Config::default()
+    };
+    let resp = call_configured_server(config, req()).await;
+    assert_resp_success(&resp);
+    let headers = resp.headers();
+    assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "text/vtt");
+    assert_eq!(headers.get(header::CONTENT_ENCODING).unwrap(), "br");
+}
+
+/// already compressed image formats are served as is
+#[tokio::test]
+async fn image_types() {
+    let cases = [
+        ("/img/pixel.jpg", "image/jpeg"),
+        ("/img/pixel.gif", "image/gif"),
+        ("/favicon.ico", "image/x-icon"),
+    ]
+    .map(|(path, ty)| {
+        Case::get(path)
+            .header(header::ACCEPT_ENCODING, "gzip, br")
+            .expect_header(header::CONTENT_TYPE, ty)
+            .expect_header(header::ACCEPT_RANGES, "bytes")
+            .expect_no_header(header::CONTENT_ENCODING)
+            .expect_no_header(header::VARY)
+    });
+    check_cases(router(site_dir(), Config::default()).unwrap(), cases).await;
+}
+
+#[tokio::test]
+async fn ranges() {
+    let app = router(site_dir(), Config::default()).unwrap();
+    let path = "/img/favicon.png";
+    let favicon = std::fs::read(site_dir().join("img").join("favicon.png")).unwrap();
+    let len = favicon.len();
+    let resp = call_router(app.clone(), get_req(path)).await;
+    let e_tag = resp.headers().get(header::ETAG).unwrap().to_owned();
+    let e_tag = e_tag.to_str().unwrap();
+    let range = |range: &'static str| Case::get(path).header(header::RANGE, range);
+    let cases = [
+        range("bytes=0-3")
+            .expect_status(StatusCode::PARTIAL_CONTENT)
+            .expect_header(header::CONTENT_RANGE, format!("bytes 0-3/{len}"))
+            .expect_header(header::CONTENT_LENGTH, "4")
+            .expect_body(ExpectedBody::Exact(b"\x89PNG".to_vec())),
+        range("bytes=-2")
+            .expect_status(StatusCode::PARTIAL_CONTENT)
+            .expect_header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{len}", len - 2, len - 1),
+            )
+            .expect_body(ExpectedBody::Exact(favicon[len - 2..].to_vec())),
+        range("bytes=100-")
+            .expect_status(StatusCode::PARTIAL_CONTENT)
+            .expect_body(ExpectedBody::Exact(favicon[100..].to_vec())),
+        Case::head(path)
+            .header(header::RANGE, "bytes=0-3")
+            .expect_status(StatusCode::PARTIAL_CONTENT)
+            .expect_header(header::CONTENT_LENGTH, "4")
+            .expect_body(ExpectedBody::Empty),
+        range("bytes=999999999-")
+            .expect_status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .expect_header(header::CONTENT_RANGE, format!("bytes */{len}"))
+            .expect_body(ExpectedBody::Empty),
+        // unsupported ranges get the full content
+        range("bytes=0-1, 4-5").expect_body(ExpectedBody::Exact(favicon.clone())),
+        range("bytes=5-2").expect_header(header::CONTENT_LENGTH, len.to_string()),
+        // as do ranges with a mismatched `If-Range`
+        range("bytes=0-3")
+            .header(header::IF_RANGE, "\"stale\"")
+            .expect_header(header::CONTENT_LENGTH, len.to_string()),
+        range("bytes=0-3")
+            .header(header::IF_RANGE, e_tag)
+            .expect_status(StatusCode::PARTIAL_CONTENT),
+        // text gets negotiated instead, so it's always sent in full
+        Case::get("/robots.txt")
+            .header(header::RANGE, "bytes=0-3")
+            .expect_no_header(header::ACCEPT_RANGES)
+            .expect_no_header(header::CONTENT_RANGE),
+    ];
+    check_cases(app, cases).await;
+}
+
+/// file names that can't appear as is in a URL get percent-encoded or skipped
+#[tokio::test]
+async fn url_unsafe_names() {
+    let resp = call_test_server(get_req("/posts/c%23%20and%20f%23.html")).await;
+    assert_resp_success(&resp);
+    let body = body_string(resp.into_body()).await.unwrap();
+    assert!(body.contains("<h1>C# and F#</h1>"), "{body}");
+    // the `#` would otherwise start a fragment
+    let resp = call_test_server(get_req("/posts/c")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let config = Config {
+        url_unsafe_names: UrlUnsafeNames::Skip,
+        ..Config::default()
+    };
+    let resp = call_configured_server(config, get_req("/posts/c%23%20and%20f%23.html")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+/// a single file can be served instead of a directory
+#[tokio::test]
+async fn single_file() {
+    let file = site_dir().join("sitemap.xml");
+    let config = Config::load(&file).unwrap();
+    let app = router(file, config).unwrap();
+
+    for path in ["/", "/sitemap.xml"] {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_resp_success(&resp);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/xml; charset=utf-8",
+            "path: {path}"
+        );
+        let body = body_string(resp.into_body()).await.unwrap();
+        assert!(body.starts_with("<?xml"), "{body}");
+    }
+
+    // nothing else from the directory gets served
+    let resp = call_router(app, get_req("/robots.txt")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+/// feeds get their own content types while staying compressible
+#[tokio::test]
+async fn rss_feed() {
+    let mut req = get_req("/feed.rss");
+    req.headers_mut()
+        .insert(header::ACCEPT_ENCODING, HeaderValue::from_static("br"));
+    let resp = call_test_server(req).await;
+    assert_resp_success(&resp);
+    let headers = resp.headers();
+    assert_eq!(
+        headers.get(header::CONTENT_TYPE).unwrap(),
+        "application/rss+xml; charset=utf-8"
+    );
+    assert_eq!(headers.get(header::CONTENT_ENCODING).unwrap(), "br");
+    assert!(headers.contains_key(header::ETAG));
+}
+
+/// a `robots.txt` gets generated when configured and the site lacks one
+#[tokio::test]
+async fn generated_robots_txt() {
+    let config = || Config {
+        robots: Some(Robots {
+            policy: RobotsPolicy::DisallowAll,
+            sitemap: Some("https://sample.site/sitemap.xml".into()),
+        }),
+        ..Config::default()
+    };
+
+    // serving a single file leaves us without a `robots.txt`
+    let app = router(site_dir().join("sitemap.xml"), config()).unwrap();
+    let cases = [
+        Case::get("/robots.txt")
+            .expect_header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .expect_body(ExpectedBody::Exact(
+                b"User-agent: *\nDisallow: /\nSitemap: https://sample.site/sitemap.xml\n".into(),
+            )),
+        // and it goes through the usual pipeline
+        Case::get("/robots.txt")
+            .header(header::ACCEPT_ENCODING, "br")
+            .expect_header(header::CONTENT_ENCODING, "br"),
+    ];
+    check_cases(app, cases).await;
+
+    // while a real one takes precedence
+    let app = router(site_dir(), config()).unwrap();
+    let case = Case::get("/robots.txt").expect_body(ExpectedBody::Exact(
+        std::fs::read(site_dir().join("robots.txt")).unwrap(),
+    ));
+    check_cases(app, [case]).await;
+}
+
+/// empty files are always served as identity regardless of what gets negotiated
+#[tokio::test]
+async fn empty_file() {
+    let req = || {
+        let mut req = get_req("/empty.html");
+        req.headers_mut().insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip, br"),
+        );
+        req
+    };
+
+    let resp = call_test_server(req()).await;
+    assert_resp_success(&resp);
+    assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+    assert_eq!(resp.headers().get(header::CONTENT_LENGTH).unwrap(), "0");
+    let e_tag = resp.headers().get(header::ETAG).unwrap().clone();
+    assert!(body_vec(resp.into_body()).await.unwrap().is_empty());
+
+    // and the e-tag is stable
+    let resp = call_test_server(req()).await;
+    assert_eq!(resp.headers().get(header::ETAG).unwrap(), e_tag);
+}
+
+/// without any encodings compression is disabled entirely
+#[tokio::test]
+async fn compression_disabled() {
+    let config = Config {
+        encodings: Vec::new(),
+        ..Config::default()
+    };
+    let mut req = get_req("/sitemap.xml");
+    req.headers_mut().insert(
+        header::ACCEPT_ENCODING,
+        HeaderValue::from_static("gzip, br"),
+    );
+    let resp = call_configured_server(config, req).await;
+    assert_resp_success(&resp);
+    let headers = resp.headers();
+    assert!(!headers.contains_key(header::CONTENT_ENCODING));
+    assert!(!headers.contains_key(header::VARY));
+    let body = body_string(resp.into_body()).await.unwrap();
+    assert!(body.starts_with("<?xml"), "{body}");
+}
+
+/// `.well-known/` gets walked like everything else with extension-less files being plain text
+#[tokio::test]
+async fn well_known() {
+    let resp = call_test_server(get_req("/.well-known/security.txt")).await;
+    assert_resp_success(&resp);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/plain; charset=utf-8"
+    );
+    let body = body_string(resp.into_body()).await.unwrap();
+    assert!(body.starts_with("Contact: "), "{body}");
+
+    let resp = call_test_server(get_req("/.well-known/acme-challenge/some-acme-token")).await;
+    assert_resp_success(&resp);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/plain; charset=utf-8"
+    );
+}
+
+/// only the configured encodings get stored, and negotiation falls back to identity when the client
+/// accepts none of them
+#[tokio::test]
+async fn single_encoding() {
+    let req = |accept_encoding| {
+        let mut req = get_req("/sitemap.xml");
+        req.headers_mut().insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static(accept_encoding),
+        );
+        req
+    };
+    let config = || Config {
+        encodings: vec![Encoding::Brotli],
+        ..Config::default()
+    };
+
+    let resp = call_configured_server(config(), req("gzip, br")).await;
+    assert_resp_success(&resp);
+    assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+    // there's no choice to hint at
+    assert!(!resp.headers().contains_key(header::ACCEPT_ENCODING));
+
+    let resp = call_configured_server(config(), req("gzip")).await;
+    assert_resp_success(&resp);
+    assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+    assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+    let body = body_string(resp.into_body()).await.unwrap();
+    assert!(body.starts_with("<?xml"), "{body}");
+}
+
+/// the compression audit flags files with a compression ratio over the threshold
+#[test]
+fn compression_audit() {
+    let mut config = Config::default();
+    let poorly_compressed = audit_compression(&site_dir(), &config);
+    // tiny files don't have enough content to compress well
+    // files are always walked in sorted order
+    let paths: Vec<_> = poorly_compressed.iter().map(|poor| &*poor.path).collect();
+    assert_eq!(
+        paths,
+        [
+            ".well-known/acme-challenge/some-acme-token",
+            "binary.txt",
+            "robots.txt"
+        ]
+    );
+    for PoorCompression { path, ratio } in &poorly_compressed {
+        match ratio {
+            Some(ratio) => assert!(*ratio > config.poor_compression_ratio),
+            // binary content behind a text extension can't be compressed at all
+            None => assert_eq!(path, "binary.txt"),
+        }
+    }
+
+    // non-UTF-8 files get reported regardless of the threshold
+    config.poor_compression_ratio = 1.5;
+    let paths: Vec<_> = audit_compression(&site_dir(), &config)
+        .into_iter()
+        .map(|poor| poor.path)
+        .collect();
+    assert_eq!(paths, ["binary.txt"]);
+}
+
+fn temp_access_log(name: &str) -> PathBuf {
+    let log_path = std::env::temp_dir().join(format!("{name}-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&log_path);
+    log_path
+}
+
+/// reads (and cleans up) the access log after waiting for entries to be written
+async fn read_access_log(log_path: &Path) -> String {
+    // the entries get written in the background, so give it a bit
+    let mut contents = String::new();
+    for _ in 0..100 {
+        contents = std::fs::read_to_string(log_path).unwrap();
+        if !contents.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    std::fs::remove_file(log_path).unwrap();
+    contents
+}
+
+/// requests get written to the configured access log
+#[tokio::test]
+async fn json_access_log() {
+    let log_path = temp_access_log("json-access-log");
+    let config = Config {
+        access_log: Some(AccessLog {
+            format: LogFormat::Json,
+            path: Some(log_path.clone()),
+        }),
+        ..Config::default()
+    };
+    let mut req = get_req("/robots.txt?a=b");
+    req.headers_mut()
+        .insert(header::USER_AGENT, HeaderValue::from_static("test-agent"));
+    let resp = call_configured_server(config, req).await;
+    assert_resp_success(&resp);
+
+    let contents = read_access_log(&log_path).await;
+    let entry: serde_json::Value = serde_json::from_str(contents.trim_end()).unwrap();
+    assert_eq!(entry["method"], "GET");
+    assert_eq!(entry["path"], "/robots.txt?a=b");
+    assert_eq!(entry["status"], 200);
+    assert_eq!(entry["bytes"], 54);
+    assert_eq!(entry["user_agent"], "test-agent");
+    // the router is called directly, so there's no client
+    assert!(entry["client_ip"].is_null());
+}
+
+/// the client's address gets picked up from the connection info when the server provides it
+#[tokio::test]
+async fn access_log_client_ip() {
+    let log_path = temp_access_log("client-ip-access-log");
+    let config = Config {
+        access_log: Some(AccessLog {
+            format: LogFormat::Json,
+            path: Some(log_path.clone()),
+        }),
+        ..Config::default()
+    };
+    let mut req = get_req("/robots.txt");
+    // what `into_make_service_with_connect_info()` sets for every request
+    let client_addr: SocketAddr = ([192, 0, 2, 7], 40_000).into();
+    req.extensions_mut().insert(ConnectInfo(client_addr));
+    let resp = call_configured_server(config, req).await;
+    assert_resp_success(&resp);
+
+    let contents = read_access_log(&log_path).await;
+    let entry: serde_json::Value = serde_json::from_str(contents.trim_end()).unwrap();
+    assert_eq!(entry["client_ip"], "192.0.2.7");
+}
+
+/// the cache scope can be picked by content type and then overridden by path
+#[tokio::test]
+async fn cache_scope() {
+    let config = Config {
+        cache_scope: CacheScopes {
+            default: CacheScope::Public,
+            types: [(ContentType::Html, CacheScope::Private)].into(),
+            paths: vec![PathCacheScope {
+                globs: PathGlobs::new(["/posts/hello-world/*"]).unwrap(),
+                scope: CacheScope::NoStore,
+            }],
+        },
+        ..Config::default()
+    };
+    let app = router(site_dir(), config).unwrap();
+    let cases = [
+        ("/robots.txt", "public, max-age=300"),
+        ("/posts/", "private, max-age=300"),
+        ("/posts/hello-world/", "no-store, no-cache, must-revalidate"),
+    ];
+    for (path, cache_control) in cases {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_resp_success(&resp);
+        let resp_cache_control = resp.headers().get(header::CACHE_CONTROL).unwrap();
+        assert_eq!(resp_cache_control, cache_control, "path: {path}");
+    }
+}
+
+/// no-store files never carry an e-tag and can't be revalidated
+#[tokio::test]
+async fn no_store() {
+    let config = Config {
+        no_store: PathGlobs::new(["/robots.txt"]).unwrap(),
+        ..Config::default()
+    };
+    let app = router(site_dir(), config).unwrap();
+
+    let resp = call_router(app.clone(), get_req("/robots.txt")).await;
+    assert_resp_success(&resp);
+    let headers = resp.headers();
+    assert_eq!(
+        headers.get(header::CACHE_CONTROL).unwrap(),
+        "no-store, no-cache, must-revalidate"
+    );
+    assert!(!headers.contains_key(header::ETAG));
+
+    // even what would've been a matching tag gets the full response
+    let resp = call_test_server(get_req("/robots.txt")).await;
+    let robots_e_tag = resp.headers().get(header::ETAG).unwrap().to_owned();
+    let mut req = get_req("/robots.txt");
+    req.headers_mut()
+        .insert(header::IF_NONE_MATCH, robots_e_tag);
+    let resp = call_router(app, req).await;
+    assert_resp_success(&resp);
+}
+
+#[tokio::test]
+async fn no_cache_mode() {
+    let config = Config {
+        no_cache: true,
+        ..Config::default()
+    };
+    let app = router(site_dir(), config).unwrap();
+    let resp = call_test_server(get_req("/")).await;
+    let e_tag = resp.headers().get(header::ETAG).unwrap().to_owned();
+    let e_tag = e_tag.to_str().unwrap();
+    let no_store = "no-store, no-cache, must-revalidate";
+    let cases = [
+        Case::get("/")
+            .header(header::IF_NONE_MATCH, e_tag)
+            .expect_header(header::CACHE_CONTROL, no_store)
+            .expect_no_header(header::ETAG)
+            .expect_no_header(header::LAST_MODIFIED),
+        Case::get("/img/favicon.png")
+            .expect_header(header::CACHE_CONTROL, no_store)
+            .expect_no_header(header::ETAG),
+        // status code pages too
+        Case::get("/not-found")
+            .expect_status(StatusCode::NOT_FOUND)
+            .expect_header(header::CACHE_CONTROL, no_store)
+            .expect_no_header(header::ETAG),
+    ];
+    check_cases(app, cases).await;
+}
+
+/// malformed paths get rejected before they're routed and are kept out of the access log
+#[tokio::test]
+async fn malformed_paths_rejected() {
+    let log_path = temp_access_log("malformed-access-log");
+    let config = Config {
+        access_log: Some(AccessLog {
+            format: LogFormat::Combined,
+            path: Some(log_path.clone()),
+        }),
+        ..Config::default()
+    };
+    let app = router(site_dir(), config).unwrap();
+    for path in ["/%00", "/posts/%00/", "/%ff%fe.html", "/%c3%28"] {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "path: {path}");
+    }
+    let resp = call_router(app, get_req("/robots.txt")).await;
+    assert_resp_success(&resp);
+
+    let contents = read_access_log(&log_path).await;
+    let lines: Vec<_> = contents.lines().collect();
+    assert_eq!(lines.len(), 1, "{contents}");
+    assert!(lines[0].contains("GET /robots.txt "), "{contents}");
+}
+
+/// probes get rejected before they're routed and are kept out of the access log
+#[tokio::test]
+async fn probes_rejected() {
+    let log_path = temp_access_log("probes-access-log");
+    let config = Config {
+        probes: Probes {
+            paths: PathGlobs::new(["/.env", "/.git/**", "**/wp-login.php"]).unwrap(),
+            status: StatusCode::FORBIDDEN,
+        },
+        access_log: Some(AccessLog {
+            format: LogFormat::Combined,
+            path: Some(log_path.clone()),
+        }),
+        ..Config::default()
+    };
+    let app = router(site_dir(), config).unwrap();
+    for path in ["/.env", "/.git/config", "/blog/wp-login.php", "//.env"] {
+        let resp = call_router(app.clone(), get_req(path)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "path: {path}");
+    }
+    let resp = call_router(app, get_req("/robots.txt")).await;
+    assert_resp_success(&resp);
+
+    let contents = read_access_log(&log_path).await;
+    let lines: Vec<_> = contents.lines().collect();
+    assert_eq!(lines.len(), 1, "{lines:#?}");
+    assert!(
+        lines[0].contains("\"GET /robots.txt HTTP/1.1\" 200"),
+        "{}",
+        lines[0]
+    );
+}
+
+/// numeric pages outside of the status page dir are served as normal content
+#[tokio::test]
+async fn unconfigured_status_code_page_reachable() {
+    let resp = call_test_server(get_req("/408.html")).await;
+    assert_resp_success(&resp);
+    let body = body_string(resp.into_body()).await.unwrap();
+    assert!(body.contains("<h1>408 REQUEST TIMEOUT</h1>"), "{body}");
+}
+
+/// status code pages can be picked up from the root of the site instead
+#[tokio::test]
+async fn root_status_page_dir() {
+    let mut config = Config {
+        status_page_dir: String::new(),
+        ..Config::default()
+    };
+    config.status_pages.insert(StatusCode::REQUEST_TIMEOUT);
+    let app = router(site_dir(), config).unwrap();
+
+    let resp = call_router(app.clone(), get_req("/408.html")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    // and the dedicated dir is just normal content now
+    let resp = call_router(app, get_req("/_status/404.html")).await;
+    assert_resp_success(&resp);
+}
+
+/// status code pages live in their own dir and aren't reachable directly
 #[tokio::test]
 async fn status_code_page_not_found() {
-    let req = get_req("/408.html");
+    let req = get_req("/_status/404.html");
     let resp = call_test_server(req).await;
     let snap_resp = SnapTextResp::new(resp).await;
     insta::assert_snapshot!(
@@ -169,8 +1442,10 @@ async fn status_code_page_not_found() {
         404 - Not Found
           content-length: 519
             content-type: text/html; charset=utf-8
+                    date: [http-date]
                     etag: "7e03829c89f8eb3f"
                   server: a-blog-out-of-deep-space 0.1.0
+                    vary: accept-encoding
         ---
         <!doctype html>
         <html lang="en">
@@ -202,6 +1477,128 @@ async fn status_code_page_can_be_compressed() {
     assert!(!resp_headers.contains_key(header::ACCEPT_ENCODING));
 }
 
+/// `Content-Length` always matches what gets sent, and `HEAD` gets the same length as `GET`
+#[tokio::test]
+async fn content_length_parity() {
+    let configured = router(site_dir(), Config::default()).unwrap();
+    let no_status_pages = Config {
+        status_pages: Default::default(),
+        ..Config::default()
+    };
+    let unconfigured = router(site_dir(), no_status_pages).unwrap();
+    let paths = [
+        "/",
+        "/robots.txt",
+        "/img/favicon.png",
+        "/empty.html",
+        "/not-found",
+    ];
+    for (app, path) in [&configured, &unconfigured]
+        .into_iter()
+        .flat_map(|app| paths.map(|path| (app, path)))
+    {
+        for accept_encoding in ["identity", "gzip", "br"] {
+            let req = |method| {
+                Request::builder()
+                    .method(method)
+                    .uri(path)
+                    .header(header::ACCEPT_ENCODING, accept_encoding)
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let context = format!("{path} with {accept_encoding}");
+            let content_length = |resp: &Response| {
+                resp.headers()
+                    .get(header::CONTENT_LENGTH)
+                    .unwrap_or_else(|| panic!("{context}: missing content-length"))
+                    .to_str()
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap()
+            };
+
+            let get_resp = call_router(app.clone(), req(Method::GET)).await;
+            let get_len = content_length(&get_resp);
+            let body = body_vec(get_resp.into_body()).await.unwrap();
+            assert_eq!(get_len, body.len(), "{context}");
+
+            let head_resp = call_router(app.clone(), req(Method::HEAD)).await;
+            assert_eq!(content_length(&head_resp), get_len, "{context}");
+            let body = body_vec(head_resp.into_body()).await.unwrap();
+            assert!(body.is_empty(), "{context}");
+        }
+    }
+}
+
+/// status code pages can be revalidated too, but keep their status code otherwise
+#[tokio::test]
+async fn status_code_page_revalidation() {
+    let resp = call_test_server(get_req("/not-found")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let etag = resp.headers().get(header::ETAG).unwrap().to_owned();
+
+    let mut req = get_req("/not-found");
+    req.headers_mut().insert(header::IF_NONE_MATCH, etag);
+    let resp = call_test_server(req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    let body = body_vec(resp.into_body()).await.unwrap();
+    assert!(body.is_empty());
+
+    let mut req = get_req("/not-found");
+    req.headers_mut()
+        .insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"stale\""));
+    let resp = call_test_server(req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+/// every response gets an accurate `Date`, including the ones that never reach a route
+#[tokio::test]
+async fn date_header() {
+    /// Parses an IMF-fixdate into seconds since the unix epoch
+    fn parse_imf_fixdate(date: &str) -> u64 {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        // `Sun, 06 Nov 1994 08:49:37 GMT`
+        let parts: Vec<_> = date.split([' ', ':']).collect();
+        let [_, day, month, year, hour, minute, second, "GMT"] = parts[..] else {
+            panic!("not an IMF-fixdate: {date}");
+        };
+        let num = |part: &str| part.parse::<u64>().unwrap();
+        let month = MONTHS.iter().position(|&name| name == month).unwrap() as u64 + 1;
+        // days from civil: https://howardhinnant.github.io/date_algorithms.html
+        let year = if month <= 2 { num(year) - 1 } else { num(year) };
+        let (era, year_of_era) = (year / 400, year % 400);
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + num(day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        days * 86_400 + num(hour) * 3_600 + num(minute) * 60 + num(second)
+    }
+
+    for (path, status) in [
+        ("/", StatusCode::OK),
+        ("/not-found", StatusCode::NOT_FOUND),
+        ("/%00", StatusCode::BAD_REQUEST),
+    ] {
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let resp = call_test_server(get_req(path)).await;
+        let after = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert_eq!(resp.status(), status, "{path}");
+        let date = resp.headers().get(header::DATE).unwrap().to_str().unwrap();
+        let date = parse_imf_fixdate(date);
+        assert!(
+            (before..=after).contains(&date),
+            "{path}: {date} not in {before}..={after}"
+        );
+    }
+}
+
 /// server supports etag based revalidation to support client http caches
 #[tokio::test]
 async fn revalidation() {
@@ -223,14 +1620,69 @@ async fn revalidation() {
     let snap_resp = SnapTextResp::new(resp).await;
     insta::assert_snapshot!(
         snap_resp,
-        @r"
+        @r#"
         304 - Not Modified
-           cache-control: max-age=300
+           cache-control: public, max-age=300
           content-length: 0
             content-type: image/png
+                    date: [http-date]
+                    etag: "15aadb2fc72590ae"
                   server: a-blog-out-of-deep-space 0.1.0
-        ",
+        "#,
     );
+
+    // compressible files vary by encoding on the `304` too
+    let resp = call_test_server(get_req("/")).await;
+    let etag = resp.headers().get(header::ETAG).unwrap().clone();
+    let mut req = get_req("/");
+    req.headers_mut()
+        .insert(header::IF_NONE_MATCH, etag.clone());
+    let resp = call_test_server(req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers().get(header::ETAG), Some(&etag));
+    assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+}
+
+/// huge files get a weak e-tag that still revalidates while small files keep their strong one
+#[tokio::test]
+async fn weak_e_tag_revalidation() {
+    let config = || Config {
+        weak_e_tag_min_size: Some(100 * 1_024),
+        ..Config::default()
+    };
+
+    let resp = call_configured_server(config(), get_req("/robots.txt")).await;
+    let e_tag = resp.headers().get(header::ETAG).unwrap();
+    assert!(e_tag.to_str().unwrap().starts_with('"'), "{e_tag:?}");
+
+    let resp = call_configured_server(config(), get_req("/img/favicon.png")).await;
+    assert_resp_success(&resp);
+    let e_tag = resp.headers().get(header::ETAG).unwrap().to_owned();
+    assert!(e_tag.to_str().unwrap().starts_with("W/\""), "{e_tag:?}");
+
+    let mut req = get_req("/img/favicon.png");
+    req.headers_mut().insert(header::IF_NONE_MATCH, e_tag);
+    let resp = call_configured_server(config(), req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+}
+
+/// negotiable responses always `Vary` on `Accept-Encoding` even when served as identity, while
+/// responses that never get compressed don't mention encodings at all
+#[tokio::test]
+async fn encoding_hints() {
+    let resp = call_test_server(get_req("/sitemap.xml")).await;
+    assert_resp_success(&resp);
+    let headers = resp.headers();
+    assert_eq!(headers.get(header::VARY).unwrap(), "accept-encoding");
+    assert_eq!(headers.get(header::ACCEPT_ENCODING).unwrap(), "gzip, br");
+    assert!(!headers.contains_key(header::CONTENT_ENCODING));
+
+    let resp = call_test_server(get_req("/img/favicon.png")).await;
+    assert_resp_success(&resp);
+    let headers = resp.headers();
+    assert!(!headers.contains_key(header::VARY));
+    assert!(!headers.contains_key(header::ACCEPT_ENCODING));
+    assert!(!headers.contains_key(header::CONTENT_ENCODING));
 }
 
 /// server supports serving compressed content through proactive-content negotiation
@@ -242,7 +1694,7 @@ async fn proactive_content_negotiation() {
 
         use flate2::read::GzDecoder;
 
-        let mut decoder = GzDecoder::new(&compressed[..]);
+        let mut decoder = GzDecoder::new(compressed);
         let mut text = String::new();
         decoder.read_to_string(&mut text).unwrap();
         text
@@ -276,3 +1728,245 @@ async fn proactive_content_negotiation() {
     // which should be equal to the decompressed body
     assert_eq!(uncompress_text(&compressed_body), full_body);
 }
+
+#[tokio::test]
+async fn debug_header() {
+    let debug = HeaderName::from_static("x-aboods-debug");
+    let resp = call_test_server(get_req("/")).await;
+    assert!(!resp.headers().contains_key(&debug));
+
+    let config = Config {
+        debug_header: true,
+        ..Config::default()
+    };
+    let app = router(site_dir(), config).unwrap();
+    let resp = call_router(app.clone(), get_req("/robots.txt")).await;
+    let e_tag = resp.headers().get(header::ETAG).unwrap().to_owned();
+    let cases = [
+        Case::get("/robots.txt")
+            .header(header::ACCEPT_ENCODING, "br")
+            .expect_header(
+                debug.clone(),
+                "enc=br; why=negotiated; revalidated=false; cc=\"public, max-age=300\"; \
+                route=/robots.txt",
+            ),
+        Case::get("/robots.txt")
+            .header(header::ACCEPT_ENCODING, "zstd")
+            .header(header::IF_NONE_MATCH, e_tag.to_str().unwrap())
+            .expect_status(StatusCode::NOT_MODIFIED)
+            .expect_header(
+                debug.clone(),
+                "enc=-; why=no-body; revalidated=true; cc=\"public, max-age=300\"; \
+                route=/robots.txt",
+            ),
+        Case::get("/img/favicon.png").expect_header(
+            debug.clone(),
+            "enc=identity; why=uncompressed; revalidated=false; \
+            cc=\"public, max-age=300\"; route=/img/favicon.png",
+        ),
+        Case::get("/posts/")
+            .header(header::ACCEPT_ENCODING, "zstd")
+            .expect_header(
+                debug.clone(),
+                "enc=identity; why=not-accepted; revalidated=false; \
+                cc=\"public, max-age=300\"; route=/posts/",
+            ),
+        Case::get("/not-found")
+            .expect_status(StatusCode::NOT_FOUND)
+            .expect_header(
+                debug,
+                "enc=identity; why=not-accepted; revalidated=false; cc=\"-\"; route=-",
+            ),
+    ];
+    check_cases(app, cases).await;
+}
+
+/// nothing gets negotiated by language, so `Accept-Language` must never end up in `Vary`
+#[tokio::test]
+async fn vary_is_language_agnostic() {
+    let table = [
+        ("/", Some("accept-encoding")),
+        ("/robots.txt", Some("accept-encoding")),
+        ("/not-found", Some("accept-encoding")),
+        ("/img/favicon.png", None),
+    ];
+    for (path, expected) in table {
+        let mut req = get_req(path);
+        req.headers_mut().insert(
+            header::ACCEPT_LANGUAGE,
+            HeaderValue::from_static("fr-CH, fr;q=0.9, en;q=0.8"),
+        );
+        let resp = call_test_server(req).await;
+        let vary = resp.headers().get_all(header::VARY);
+        let vary: Vec<_> = vary.iter().map(|value| value.to_str().unwrap()).collect();
+        assert_eq!(vary, Vec::from_iter(expected), "{path}");
+    }
+}
+
+/// matching user agents get served uncompressed regardless of what they accept
+#[tokio::test]
+async fn identity_user_agents() {
+    let config = Config {
+        identity_user_agents: UserAgentPatterns::new([r"MSIE [56]\."]).unwrap(),
+        ..Config::default()
+    };
+    let app = router(site_dir(), config).unwrap();
+    let req = |path, user_agent| {
+        Request::get(path)
+            .header(header::ACCEPT_ENCODING, "br, gzip")
+            .header(header::USER_AGENT, user_agent)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let ancient = "Mozilla/4.0 (compatible; MSIE 6.0; Windows NT 5.1)";
+    let resp = call_router(app.clone(), req("/", ancient)).await;
+    assert_resp_success(&resp);
+    assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+    assert_eq!(
+        resp.headers().get(header::VARY).unwrap(),
+        "accept-encoding, user-agent"
+    );
+
+    // everyone else still gets compression, but caches have to tell the two apart
+    let resp = call_router(app.clone(), req("/", "curl/8.0")).await;
+    assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+    assert_eq!(
+        resp.headers().get(header::VARY).unwrap(),
+        "accept-encoding, user-agent"
+    );
+
+    // and nothing changes for responses that never varied in the first place
+    let resp = call_router(app, req("/img/favicon.png", ancient)).await;
+    assert!(!resp.headers().contains_key(header::VARY));
+}
+
+#[tokio::test]
+async fn throttle() {
+    let config = Config {
+        throttle: Some(Throttle {
+            latency_ms: 50,
+            bytes_per_sec: Some(NonZeroU64::new(6_540).unwrap()),
+        }),
+        ..Config::default()
+    };
+    let mut req = get_req("/index.html");
+    req.headers_mut().insert(
+        header::ACCEPT_ENCODING,
+        HeaderValue::from_static("identity"),
+    );
+    let start = Instant::now();
+    let resp = call_configured_server(config, req).await;
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(resp.headers().get(header::CONTENT_LENGTH).unwrap(), "654");
+    // ~0.1s to send the 654 byte body
+    let body = body_vec(resp.into_body()).await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    let expected = std::fs::read(site_dir().join("index.html")).unwrap();
+    assert_eq!(body, expected);
+}
+
+#[tokio::test]
+async fn serve_and_shutdown() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let app = router(site_dir(), Config::default()).unwrap();
+    let (server, shutdown) = serve(listener, app, Duration::from_secs(30)).unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    stream
+        .write_all(b"GET /robots.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await.unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+
+    shutdown.shutdown();
+    server.wait().await.unwrap();
+}
+
+/// draining the recorder after shutdown gets every entry into the access log without waiting
+#[tokio::test]
+async fn drain_recorder() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    let log_path = temp_access_log("drain-access-log");
+    let config = Config {
+        access_log: Some(AccessLog {
+            format: LogFormat::Combined,
+            path: Some(log_path.clone()),
+        }),
+        ..Config::default()
+    };
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let (app, recorder) = router_with_recorder(site_dir(), config).unwrap();
+    let (server, shutdown) = serve(listener, app, Duration::from_secs(30)).unwrap();
+
+    for _ in 0..5 {
+        let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        stream
+            .write_all(b"GET /robots.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+    }
+
+    shutdown.shutdown();
+    server.wait().await.unwrap();
+    recorder.drain().await;
+    let contents = std::fs::read_to_string(&log_path).unwrap();
+    let _ = std::fs::remove_file(&log_path);
+    assert_eq!(contents.lines().count(), 5, "{contents}");
+}
+
+/// connections that outlive the drain timeout get forcibly closed
+#[tokio::test]
+async fn drain_timeout() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    // a slow client stuck downloading something big
+    let config = Config {
+        throttle: Some(Throttle {
+            latency_ms: 0,
+            bytes_per_sec: Some(NonZeroU64::new(1_000).unwrap()),
+        }),
+        ..Config::default()
+    };
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let app = router(site_dir(), config).unwrap();
+    let drain_timeout = Duration::from_millis(100);
+    let (server, shutdown) = serve(listener, app, drain_timeout).unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    stream
+        .write_all(b"GET /img/favicon.png HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buf = [0; 64];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+    let start = Instant::now();
+    shutdown.shutdown();
+    tokio::time::timeout(Duration::from_secs(5), server.wait())
+        .await
+        .expect("lingering connections should get closed")
+        .unwrap();
+    assert!(start.elapsed() >= drain_timeout);
+    // and the client sees the connection go away without the full body
+    let mut rest = Vec::new();
+    let _ = stream.read_to_end(&mut rest).await;
+    assert!(rest.len() < 246_618);
+}